@@ -0,0 +1,26 @@
+use buttondeck::DeckError;
+use hidapi::HidApi;
+use log::error;
+
+
+
+fn main() {
+
+    env_logger::init();
+    if let Err(e) = main_with_result() {
+        error!("Main: {:?}", e)
+    }
+
+}
+
+fn main_with_result() -> Result<(),DeckError> {
+
+    let mut hidapi = HidApi::new()?;
+
+    for d in buttondeck::discover(&mut hidapi)? {
+        println!("{:?} {} serial={:?} path={:?} firmware={:?} keys={:?}",
+            d.kind, d.model, d.serial, d.path, d.firmware, d.keys);
+    }
+
+    Ok(())
+}
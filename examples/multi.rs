@@ -0,0 +1,39 @@
+use buttondeck::{DeckError, ButtonDeckBuilder, ButtonDecks, DeviceKind};
+use log::{error, info};
+
+
+
+fn main() {
+
+    env_logger::init();
+    if let Err(e) = main_with_result() {
+        error!("Main: {:?}", e)
+    }
+
+}
+
+fn main_with_result() -> Result<(),DeckError> {
+
+    let args: Vec<String> = std::env::args().collect();
+
+    info!("Hello, decks!");
+
+    let mut decks = ButtonDecks::new();
+
+    // one deck per serial number given on the command line,
+    // the configs are picked from "devices" by serial or model
+    for serial in args.iter().skip(1) {
+        let builder = ButtonDeckBuilder::<()>::new(DeviceKind::StreamDeck)
+            .with_config("demo/deck.json")
+            .with_serial(serial);
+        decks.spawn(serial, builder)?;
+    }
+
+    // and one for any other stream deck
+    decks.spawn("any", ButtonDeckBuilder::<()>::new(DeviceKind::StreamDeck)
+        .with_config("demo/deck.json"))?;
+
+    decks.join();
+
+    Ok(())
+}
@@ -65,6 +65,7 @@ fn main_with_result() -> Result<()> {
 
     let mut deck = ButtonDeckBuilder::<()>::new(DeviceKind::StreamDeck)
         .with_config("demo/panoo.json")
+        .with_hot_reload(true)
         .with_function("mute_notify", mute_notify )
         .with_function("toggle_mute", toggle_mute )
         .build()?;
@@ -0,0 +1,17 @@
+// print the JSON Schema of the config files, e.g.
+//   cargo run --example schema > buttondeck.schema.json
+//   cargo run --example schema device > device.schema.json
+
+fn main() {
+
+    let schema = match std::env::args().nth(1).as_deref() {
+        Some("device") => buttondeck::device_config_schema(),
+        _ => buttondeck::config_schema()
+    };
+
+    match serde_json::to_string_pretty(&schema) {
+        Ok(s) => println!("{}", s),
+        Err(e) => eprintln!("{}", e)
+    }
+
+}
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use image::{AnimationDecoder, DynamicImage, Frame};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use log::{debug, warn};
+
+use crate::{ButtonColor, ButtonId, ButtonImage, DeckError, KeyFrame};
+
+type Result<T> = std::result::Result<T,DeckError>;
+
+const DEFAULT_BLINK: Duration = Duration::from_millis(500);
+const DEFAULT_PULSE: Duration = Duration::from_millis(1500);
+
+// time between two brightness steps of a pulse
+const PULSE_STEP: Duration = Duration::from_millis(50);
+// darkest point of a pulse
+const PULSE_MIN: f32 = 0.2;
+
+// gifs with a zero delay are played with this
+const MIN_FRAME_DELAY: Duration = Duration::from_millis(20);
+
+
+/// The `effect` of a button state
+///
+/// * `animate` plays the frames of an animated gif or png image
+/// * `blink` or `blink:#ff0000,#000000,500` switches between two colors (every 500ms)
+/// * `pulse` or `pulse:1500` fades the brightness of the key up and down
+#[derive(Clone, Debug, PartialEq)]
+pub enum Effect {
+    Animate,
+    Blink { on: Option<ButtonColor>, off: ButtonColor, interval: Duration },
+    Pulse { period: Duration },
+}
+
+impl FromStr for Effect {
+    type Err = DeckError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+
+        let (name, args) = match s.split_once(':') {
+            Some((n,a)) => (n.trim(), a.split(',').map(|a| a.trim()).collect()),
+            None => (s.trim(), Vec::new())
+        };
+
+        let millis = |a: Option<&&str>, default: Duration| -> std::result::Result<Duration, DeckError> {
+            match a {
+                Some(m) => m.parse::<u64>()
+                    .map(Duration::from_millis)
+                    .map_err(|_| DeckError::Message(format!("effect '{}': bad duration '{}'", s, m))),
+                None => Ok(default)
+            }
+        };
+
+        match name {
+            "animate" | "gif" => Ok(Effect::Animate),
+            "blink" => Ok(Effect::Blink {
+                on: args.first().map(|c| ButtonColor::from_str(c)).transpose()?,
+                off: args.get(1).map(|c| ButtonColor::from_str(c)).transpose()?
+                    .unwrap_or(ButtonColor { rgb: 0 }),
+                interval: millis(args.get(2), DEFAULT_BLINK)?,
+            }),
+            "pulse" => Ok(Effect::Pulse {
+                period: millis(args.first(), DEFAULT_PULSE)?,
+            }),
+            _ => Err(DeckError::Message(format!("unknown effect '{}'", s)))
+        }
+    }
+}
+
+
+type Frames = Arc<Vec<(ButtonImage,Duration)>>;
+
+struct KeyAnimation {
+    button: ButtonId,
+    effect: Effect,
+    frames: Option<Frames>,
+    step: usize,
+    started: Instant,
+    next: Instant,
+}
+
+
+/// Runs the effects of the keys, driven by the deck thread
+pub (crate) struct Animator {
+    keys: HashMap<usize, KeyAnimation>,
+    // decoded animations by file, with the modification time of the file
+    decoded: HashMap<PathBuf, (Option<SystemTime>, Frames)>,
+}
+
+impl Animator {
+
+    pub fn new() -> Self {
+        Animator {
+            keys: HashMap::new(),
+            decoded: HashMap::new(),
+        }
+    }
+
+    /// stop all animations (e.g. on a setup switch)
+    pub fn clear(&mut self) {
+        self.keys.clear();
+        self.prune();
+    }
+
+    pub fn stop(&mut self, index: usize) {
+        if self.keys.remove(&index).is_some() {
+            self.prune();
+        }
+    }
+
+    // forget the decoded files no running animation uses
+    fn prune(&mut self) {
+        self.decoded.retain(|_, (_, f)| Arc::strong_count(f) > 1);
+    }
+
+    /// start (or restart) the effect on a key
+    pub fn start(&mut self, index: usize, button: ButtonId, effect: &Effect, image: Option<&ButtonImage>, now: Instant) {
+
+        let frames = match effect {
+            Effect::Animate => match image.and_then(|i| i.path()) {
+                Some(p) => match self.frames(p) {
+                    Ok(f) if f.len() > 1 => Some(f),
+                    Ok(_) => {
+                        debug!("{:?} is not animated", p);
+                        None
+                    },
+                    Err(e) => {
+                        warn!("cannot decode {:?}: {:?}", p, e);
+                        None
+                    }
+                },
+                None => {
+                    warn!("effect 'animate' needs an image file");
+                    None
+                }
+            },
+            _ => None
+        };
+
+        if *effect == Effect::Animate && frames.is_none() {
+            self.stop(index);
+            return;
+        }
+
+        let replaced = self.keys.insert(index, KeyAnimation {
+            button,
+            effect: effect.clone(),
+            frames,
+            step: 0,
+            started: now,
+            next: now,
+        });
+        if replaced.is_some() {
+            self.prune();
+        }
+    }
+
+    fn frames(&mut self, path: &Path) -> Result<Frames> {
+
+        // decoded again if the file changed (e.g. edited in the assets folder)
+        let mtime = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+        if let Some((m, f)) = self.decoded.get(path) {
+            if *m == mtime {
+                return Ok(f.clone());
+            }
+            debug!("animation changed: {:?}", path);
+        }
+
+        let frames: Frames = Arc::new(decode_frames(path)?);
+        self.decoded.insert(path.to_path_buf(), (mtime, frames.clone()));
+        Ok(frames)
+    }
+
+    /// the next time `tick` has something to do
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.keys.values().map(|k| k.next).min()
+    }
+
+    /// change `frame` to the current step of the animation of each key that is due
+    pub fn tick<F>(&mut self, now: Instant, mut frame_for: F) -> Vec<(usize,KeyFrame)>
+        where F: FnMut(ButtonId) -> Option<KeyFrame>
+    {
+
+        let mut due = Vec::new();
+
+        for (index, ka) in self.keys.iter_mut() {
+
+            if ka.next > now {
+                continue;
+            }
+
+            let mut frame = match frame_for(ka.button) {
+                Some(f) => f,
+                None => continue
+            };
+
+            match &ka.effect {
+                Effect::Animate => {
+                    if let Some(frames) = &ka.frames {
+                        let (img, delay) = &frames[ka.step % frames.len()];
+                        frame.image = Some(img.clone());
+                        ka.next = now + *delay;
+                    }
+                },
+                Effect::Blink { on, off, interval } => {
+                    if ka.step % 2 == 1 {
+                        frame = KeyFrame {
+                            background: Some(off.clone()),
+                            ..KeyFrame::default()
+                        };
+                    } else if let Some(c) = on {
+                        frame.background = Some(c.clone());
+                    }
+                    ka.next = now + *interval;
+                },
+                Effect::Pulse { period } => {
+                    let t = now.duration_since(ka.started).as_secs_f32() / period.as_secs_f32().max(0.001);
+                    let wave = (1.0 + (t * std::f32::consts::TAU).cos()) / 2.0;
+                    frame.brightness = PULSE_MIN + (1.0 - PULSE_MIN) * wave;
+                    ka.next = now + PULSE_STEP;
+                },
+            }
+
+            ka.step += 1;
+            due.push((*index, frame));
+        }
+
+        due
+    }
+
+}
+
+
+// all frames of an animated gif or png
+fn decode_frames(path: &Path) -> Result<Vec<(ButtonImage,Duration)>> {
+
+    let reader = BufReader::new(File::open(path)?);
+
+    let is_png = path.extension()
+        .map(|e| e.eq_ignore_ascii_case("png") || e.eq_ignore_ascii_case("apng"))
+        .unwrap_or(false);
+
+    let frames: Vec<Frame> = if is_png {
+        let decoder = PngDecoder::new(reader)?;
+        if !decoder.is_apng() {
+            return Ok(Vec::new());
+        }
+        decoder.apng().into_frames().collect_frames()?
+    } else {
+        GifDecoder::new(reader)?.into_frames().collect_frames()?
+    };
+
+    Ok(frames.into_iter()
+        .map(|f| {
+            let delay = Duration::from(f.delay()).max(MIN_FRAME_DELAY);
+            let img = ButtonImage::from_image(&DynamicImage::ImageRgba8(f.into_buffer()))
+                .with_source(&path.to_string_lossy());
+            (img, delay)
+        })
+        .collect())
+}
@@ -1,8 +1,13 @@
-use std::{path::{PathBuf, Path}, str::FromStr};
+use std::{fmt, path::{PathBuf, Path}, str::FromStr, sync::Arc};
+
+use image::{DynamicImage, RgbaImage};
 
 use log::{warn, debug, info};
 use serde_json::Value;
-use crate::SetupId;
+use crate::SetupTarget;
+use crate::animation::Effect;
+use crate::gesture::GestureBindings;
+use crate::render::{KeyFrame, KeyLabel, TextAlign};
 use crate::{device::PhysicalKey, deck::{FnRef}, DeckError, ButtonId, StateId};
 
 type Result<T> = std::result::Result<T,DeckError>;
@@ -146,6 +151,17 @@ impl Button {
         Ok(())
     }
 
+    /// apply a change from the device to the effective value, the
+    /// result is stored in the current state and returned
+    pub fn change_value(&mut self, change: ValueChange) -> ButtonValue {
+        let next = self.effective_value().changed(change);
+        let current = self.current_state;
+        if let Some(s) = self.state_mut(current) {
+            s.value = next.clone();
+        }
+        next
+    }
+
     pub fn set_state_value(&mut self, state_name: &str, value: ButtonValue) -> Result<()> {
 
         if let Some(s) = self.state_by_name_mut(state_name) {
@@ -190,7 +206,7 @@ impl Button {
 
     pub fn effective_value<'a>(&'a self) -> &'a ButtonValue {
 
-        warn!("Button Value is {:?} {:?}", &self.current_state().value, &self.defaults.value);
+        debug!("Button Value is {:?} {:?}", &self.current_state().value, &self.defaults.value);
         
         match &self.current_state().value {
             ButtonValue::None => {
@@ -241,6 +257,75 @@ impl Button {
         }
     }
 
+    /// the text to draw on the key, if the current state (or the button) has one
+    pub fn effective_label(&self) -> Option<KeyLabel> {
+
+        let state = &self.current_state().text;
+        let defaults = &self.defaults.text;
+
+        let template = state.text.as_ref().or(defaults.text.as_ref())?;
+        let text = template
+            .replace("{label}", &self.label)
+            .replace("{value}", &self.effective_value().to_string());
+
+        let mut label = KeyLabel::new(&text);
+        label.font = state.font.clone().or_else(|| defaults.font.clone());
+        if let Some(size) = state.size.or(defaults.size) {
+            label.size = size;
+        }
+        if let Some(align) = state.align.or(defaults.align) {
+            label.align = align;
+        }
+        if let Some(color) = state.color.as_ref().or(defaults.color.as_ref()) {
+            label.color = color.clone();
+        }
+
+        Some(label)
+    }
+
+    /// the images of all states
+    pub fn images(&self) -> Vec<&ButtonImage> {
+        std::iter::once(&self.defaults).chain(self.states.iter())
+            .filter_map(|s| s.image.as_ref())
+            .collect()
+    }
+
+    /// the image, overlay and font files of all states
+    pub fn files(&self) -> Vec<&Path> {
+        std::iter::once(&self.defaults).chain(self.states.iter())
+            .flat_map(|s| {
+                let images = s.image.iter().chain(s.overlays.iter().flatten())
+                    .filter_map(|i| i.path());
+                images.chain(s.text.font.as_deref())
+            })
+            .collect()
+    }
+
+    pub fn effective_effect<'a>(&'a self) -> Option<&'a Effect> {
+        match &self.current_state().effect {
+            Some(e) => Some(e),
+            None => self.defaults.effect.as_ref()
+        }
+    }
+
+    pub fn effective_overlays<'a>(&'a self) -> &'a [ButtonImage] {
+        match &self.current_state().overlays {
+            Some(o) => o,
+            None => self.defaults.overlays.as_deref().unwrap_or(&[])
+        }
+    }
+
+    /// all layers of the key: color, image, overlays and text
+    pub fn effective_frame(&self) -> KeyFrame {
+        KeyFrame {
+            background: self.effective_color().cloned(),
+            image: self.effective_image().cloned(),
+            overlays: self.effective_overlays().to_vec(),
+            label: self.effective_label(),
+            ..KeyFrame::default()
+        }
+    }
+
     pub fn effective_color<'a>(&'a self) -> Option<&'a ButtonColor> {
         match &self.current_state().color {
             Some(c) => Some(c),
@@ -271,6 +356,51 @@ impl Button {
         }
     }
 
+    pub fn effective_on_value<'a>(&'a self) -> Option<&'a FnRef> {
+        match &self.current_state().on_value {
+            Some(c) => Some(c),
+            None => match &self.defaults.on_value {
+                Some(c) => Some(c),
+                None => None
+            }
+        }
+    }
+
+    pub fn effective_on_long_press<'a>(&'a self) -> Option<&'a FnRef> {
+        match &self.current_state().on_long_press {
+            Some(c) => Some(c),
+            None => self.defaults.on_long_press.as_ref()
+        }
+    }
+
+    pub fn effective_on_double_tap<'a>(&'a self) -> Option<&'a FnRef> {
+        match &self.current_state().on_double_tap {
+            Some(c) => Some(c),
+            None => self.defaults.on_double_tap.as_ref()
+        }
+    }
+
+    pub fn effective_long_press_setup<'a>(&'a self) -> Option<&'a SetupTarget> {
+        match &self.current_state().long_press_setup {
+            Some(c) => Some(c),
+            None => self.defaults.long_press_setup.as_ref()
+        }
+    }
+
+    pub fn effective_repeat(&self) -> bool {
+        self.current_state().repeat
+            .or(self.defaults.repeat)
+            .unwrap_or(false)
+    }
+
+    pub (crate) fn gesture_bindings(&self) -> GestureBindings {
+        GestureBindings {
+            long_press: self.effective_on_long_press().is_some() || self.effective_long_press_setup().is_some(),
+            double_tap: self.effective_on_double_tap().is_some(),
+            repeat: self.effective_repeat(),
+        }
+    }
+
     pub fn effective_switch_button_state<'a>(&'a self) -> Option<&'a StateId> {
         match &self.current_state().switch_button_state {
             Some(c) => Some(c),
@@ -281,7 +411,7 @@ impl Button {
         }
     }
 
-    pub fn effective_switch_deck_setup<'a>(&'a self) -> Option<&'a SetupId> {
+    pub fn effective_switch_deck_setup<'a>(&'a self) -> Option<&'a SetupTarget> {
         match &self.current_state().switch_deck_setup {
             Some(c) => Some(c),
             None => match &self.defaults.switch_deck_setup {
@@ -291,12 +421,40 @@ impl Button {
         }
     }
 
+    pub fn effective_page(&self) -> Option<i32> {
+        self.current_state().page.or(self.defaults.page)
+    }
 
+
+}
+
+/// Where the pixels of a [`ButtonImage`] are
+#[derive(Clone, PartialEq)]
+pub enum ImageData {
+    /// an image file, loaded when it is drawn
+    File(PathBuf),
+    /// an encoded image (png, jpeg, ...) in memory
+    Encoded(Arc<Vec<u8>>),
+    /// raw rgba pixels, 4 bytes per pixel
+    Rgba { width: u32, height: u32, pixels: Arc<Vec<u8>> },
 }
 
-#[derive(Clone,Debug)]
+impl fmt::Debug for ImageData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageData::File(p) => write!(f, "File({:?})", p),
+            ImageData::Encoded(b) => write!(f, "Encoded({} bytes)", b.len()),
+            ImageData::Rgba { width, height, .. } => write!(f, "Rgba({}x{})", width, height),
+        }
+    }
+}
+
+
+#[derive(Clone,Debug,PartialEq)]
 pub struct ButtonImage {
-    pub path: PathBuf
+    pub data: ImageData,
+    /// where the image came from (a file, "graph", "camera 1", ...), for logging
+    pub source: Option<String>,
 }
 
 impl ButtonImage {
@@ -304,20 +462,79 @@ impl ButtonImage {
 
         let p = PathBuf::from(s);
         if p.exists() {
-            Some(ButtonImage {
-                path: PathBuf::from(s)
-            })
+            Some(ButtonImage::from_file(p))
         } else {
             None
         }
     }
 
+    /// an image file, it does not need to exist yet
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        ButtonImage {
+            source: Some(path.to_string_lossy().into_owned()),
+            data: ImageData::File(path),
+        }
+    }
+
+    /// png, jpeg, bmp or gif bytes
+    pub fn from_encoded(bytes: Vec<u8>) -> Self {
+        ButtonImage {
+            data: ImageData::Encoded(Arc::new(bytes)),
+            source: None,
+        }
+    }
+
+    /// rgba pixels, row by row
+    pub fn from_rgba(width: u32, height: u32, pixels: Vec<u8>) -> Result<Self> {
+        let size = (width as usize).checked_mul(height as usize).and_then(|n| n.checked_mul(4));
+        if size != Some(pixels.len()) {
+            return Err(DeckError::Message(format!("{} bytes are no {}x{} rgba image", pixels.len(), width, height)));
+        }
+        Ok(ButtonImage {
+            data: ImageData::Rgba { width, height, pixels: Arc::new(pixels) },
+            source: None,
+        })
+    }
+
+    pub fn from_image(image: &DynamicImage) -> Self {
+        let rgba = image.to_rgba8();
+        ButtonImage {
+            data: ImageData::Rgba { width: rgba.width(), height: rgba.height(), pixels: Arc::new(rgba.into_raw()) },
+            source: None,
+        }
+    }
+
+    pub fn with_source(mut self, source: &str) -> Self {
+        self.source = Some(String::from(source));
+        self
+    }
+
+    /// the file, if the image is one
+    pub fn path(&self) -> Option<&Path> {
+        match &self.data {
+            ImageData::File(p) => Some(p),
+            _ => None
+        }
+    }
+
+    /// decode the image
+    pub fn load(&self) -> Result<DynamicImage> {
+        match &self.data {
+            ImageData::File(p) => Ok(image::open(p)?),
+            ImageData::Encoded(b) => Ok(image::load_from_memory(b)?),
+            ImageData::Rgba { width, height, pixels } => {
+                RgbaImage::from_raw(*width, *height, pixels.to_vec())
+                    .map(DynamicImage::ImageRgba8)
+                    .ok_or_else(|| DeckError::Message(String::from("rgba image with wrong size")))
+            }
+        }
+    }
+
 
     pub fn from_option_string(folder: &Path, s: &Option<String>) -> Option<Self> {
         if let Some(c) = s {
-            Some(ButtonImage {
-                path: folder.join(c)
-            })
+            Some(ButtonImage::from_file(folder.join(c)))
         } else {
             None
         }
@@ -326,12 +543,40 @@ impl ButtonImage {
 
 
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ButtonColor {
     pub rgb: u32
 }
 
 impl ButtonColor {
+
+    /// the color with brightness `f` (0.0 ..= 1.0)
+    pub fn dimmed(&self, f: f32) -> ButtonColor {
+        let (r,g,b) = self.to_rgb8();
+        let dim = |c: u8| (c as f32 * f.clamp(0.0, 1.0)) as u32;
+        ButtonColor {
+            rgb: (dim(r) << 16) | (dim(g) << 8) | dim(b)
+        }
+    }
+
+    /// red, green and blue (0..=255)
+    pub fn to_rgb8(&self) -> (u8,u8,u8) {
+        (
+            ((self.rgb & 0xff0000) >> 16) as u8,
+            ((self.rgb & 0x00ff00) >> 8) as u8,
+            (self.rgb & 0x0000ff) as u8,
+        )
+    }
+
+    /// red, green and blue in midi range (0..=127)
+    pub fn to_rgb7(&self) -> (u8,u8,u8) {
+        (
+            ((self.rgb & 0xff0000) >> 17) as u8,
+            ((self.rgb & 0x00ff00) >> 9) as u8,
+            ((self.rgb & 0x0000ff) >> 1) as u8,
+        )
+    }
+
     pub fn from_option_string(s: &Option<String>) -> Option<Self> {
         if let Some(c) = s {
             ButtonColor::from_str(c).ok()
@@ -347,11 +592,11 @@ impl FromStr for ButtonColor {
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
 
         let hex = s.trim_start_matches("#").trim_start_matches("0x");
-        let num = u32::from_str_radix(hex, 16); 
 
-        Ok(ButtonColor {
-            rgb: num.unwrap_or(0)
-        })
+        match u32::from_str_radix(hex, 16) {
+            Ok(rgb) if hex.len() <= 6 => Ok(ButtonColor { rgb }),
+            _ => Err(DeckError::Message(format!("invalid color '{}'", s)))
+        }
 
     }
 }
@@ -363,11 +608,100 @@ pub enum ButtonValue {
     Bool(bool),
     OnOff,
     String(String),
-    Number,
+    // a plain number, integer or float
+    Int(i64),
+    Number(f64),
+    // a float limited to min..=max, dials move it by step
+    Range { value: f64, min: f64, max: f64, step: f64 },
+    // the same for integers
+    IntRange { value: i64, min: i64, max: i64, step: i64 },
 
     Error(String)
 }
 
+/// A change of a value by the device.
+///
+/// Ranges scale a `Unit` to min..=max and move by their step, both clamped.
+/// Values without a range take the unit position as it is and count whole steps.
+#[derive(Debug, Clone, Copy)]
+pub enum ValueChange {
+    /// absolute position of a fader, knob or pressure (0.0 ..= 1.0)
+    Unit(f32),
+    /// relative steps of a dial
+    Steps(i32),
+}
+
+impl ButtonValue {
+
+    /// the value after a change from the device
+    pub fn changed(&self, change: ValueChange) -> ButtonValue {
+        match (self, change) {
+            (ButtonValue::Range { min, max, step, .. }, ValueChange::Unit(u)) => {
+                let v = (min + (max - min) * u as f64).max(*min).min(*max);
+                ButtonValue::Range { value: v, min: *min, max: *max, step: *step }
+            },
+            (ButtonValue::Range { value, min, max, step }, ValueChange::Steps(n)) => {
+                let v = (value + step * n as f64).max(*min).min(*max);
+                ButtonValue::Range { value: v, min: *min, max: *max, step: *step }
+            },
+            (ButtonValue::IntRange { min, max, step, .. }, ValueChange::Unit(u)) => {
+                // in i128, max - min does not fit into i64 for the widest ranges
+                let span = (*max as i128 - *min as i128) as f64;
+                let v = (*min as i128 + (span * u as f64).round() as i128).max(*min as i128).min(*max as i128) as i64;
+                ButtonValue::IntRange { value: v, min: *min, max: *max, step: *step }
+            },
+            (ButtonValue::IntRange { value, min, max, step }, ValueChange::Steps(n)) => {
+                let v = value.saturating_add(step.saturating_mul(n as i64)).max(*min).min(*max);
+                ButtonValue::IntRange { value: v, min: *min, max: *max, step: *step }
+            },
+            (ButtonValue::Int(i), ValueChange::Steps(n)) => ButtonValue::Int(i.saturating_add(n as i64)),
+            (ButtonValue::Number(f), ValueChange::Steps(n)) => ButtonValue::Number(f + n as f64),
+            (ButtonValue::Bool(_), ValueChange::Unit(u)) => ButtonValue::Bool(u >= 0.5),
+            (ButtonValue::Bool(b), ValueChange::Steps(n)) => ButtonValue::Bool(if n > 0 { true } else if n < 0 { false } else { *b }),
+            // everything else starts from scratch
+            (_, ValueChange::Unit(u)) => ButtonValue::Number(u as f64),
+            (_, ValueChange::Steps(n)) => ButtonValue::Int(n as i64),
+        }
+    }
+
+    /// the value to keep after a reload, where this is the reloaded one:
+    /// the old value, within the (maybe changed) bounds of a range
+    pub fn kept(&self, old: &ButtonValue) -> ButtonValue {
+        match (self, old) {
+            (ButtonValue::Range { min, max, step, .. }, ButtonValue::Range { value, .. }) =>
+                ButtonValue::Range { value: value.max(*min).min(*max), min: *min, max: *max, step: *step },
+            (ButtonValue::IntRange { min, max, step, .. }, ButtonValue::IntRange { value, .. }) =>
+                ButtonValue::IntRange { value: (*value).max(*min).min(*max), min: *min, max: *max, step: *step },
+            // a range that is none any more, or a new one
+            (ButtonValue::Range { .. }, _) | (ButtonValue::IntRange { .. }, _) |
+            (_, ButtonValue::Range { .. }) | (_, ButtonValue::IntRange { .. }) => self.clone(),
+            (_, ButtonValue::None) => self.clone(),
+            _ => old.clone()
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            ButtonValue::Int(i) => Some(*i as f64),
+            ButtonValue::Number(f) => Some(*f),
+            ButtonValue::Range { value, .. } => Some(*value),
+            ButtonValue::IntRange { value, .. } => Some(*value as f64),
+            ButtonValue::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+            _ => None
+        }
+    }
+
+    /// the value scaled to 0.0 ..= 1.0 (only for ranges)
+    pub fn as_unit(&self) -> Option<f64> {
+        match self {
+            ButtonValue::Range { value, min, max, .. } if max > min => Some((value - min) / (max - min)),
+            ButtonValue::IntRange { value, min, max, .. } if max > min =>
+                Some((*value as i128 - *min as i128) as f64 / (*max as i128 - *min as i128) as f64),
+            _ => None
+        }
+    }
+}
+
 impl From<String> for ButtonValue {
     fn from(s: String) -> Self {
         ButtonValue::String(s)
@@ -388,11 +722,40 @@ impl From<Value> for ButtonValue {
             Value::Null => ButtonValue::None,
             Value::Bool(b) => ButtonValue::Bool(b),
             Value::Number(n) => {
-                ButtonValue::None
+                match n.as_i64() {
+                    Some(i) => ButtonValue::Int(i),
+                    None => ButtonValue::Number(n.as_f64().unwrap_or(0.0))
+                }
             },
             Value::String(s) => ButtonValue::String(s),
             Value::Array(_) => ButtonValue::Error(String::from("Arrays are not supported in ButtonValue")),
-            Value::Object(_) => ButtonValue::Error(String::from("Objects are not supported in ButtonValue")),
+            Value::Object(o) => {
+                // { "value": 0.5, "min": 0, "max": 1, "step": 0.05 },
+                // an integer range if all of them are integers
+                let get = |k: &str| o.get(k).and_then(|v| v.as_f64());
+                let int = |k: &str| o.get(k).and_then(|v| v.as_i64());
+                let all_int = ["value", "min", "max", "step"].iter()
+                    .all(|k| o.get(*k).map(|v| v.is_i64()).unwrap_or(true));
+                match (get("min"), get("max")) {
+                    (Some(min), Some(max)) if min > max => ButtonValue::Error(format!("min {} is greater than max {}", min, max)),
+                    (Some(_), Some(_)) if all_int => {
+                        let (min, max) = (int("min").unwrap_or(0), int("max").unwrap_or(0));
+                        ButtonValue::IntRange {
+                            value: int("value").unwrap_or(min).max(min).min(max),
+                            min,
+                            max,
+                            step: int("step").unwrap_or(((max as i128 - min as i128) / 100).max(1) as i64),
+                        }
+                    },
+                    (Some(min), Some(max)) => ButtonValue::Range {
+                        value: get("value").unwrap_or(min).max(min).min(max),
+                        min,
+                        max,
+                        step: get("step").unwrap_or((max - min) / 100.0),
+                    },
+                    _ => ButtonValue::Error(String::from("Objects in ButtonValue need min and max")),
+                }
+            },
         }
     }
 }
@@ -404,7 +767,10 @@ impl ToString for ButtonValue {
             ButtonValue::Bool(b) => b.to_string(),
             ButtonValue::OnOff => String::from("on/off"),
             ButtonValue::String(s) => s.clone(),
-            ButtonValue::Number => String::from("TODO"),
+            ButtonValue::Int(i) => i.to_string(),
+            ButtonValue::Number(f) => format!("{:.2}", f),
+            ButtonValue::Range { value, .. } => format!("{:.2}", value),
+            ButtonValue::IntRange { value, .. } => value.to_string(),
             ButtonValue::Error(e) => format!("Error: {:?}", e),
         }
     }
@@ -419,6 +785,18 @@ impl Default for ButtonValue {
 
 
 
+/// Text options of a state, unset options are taken from the button defaults
+#[derive(Clone, Debug, Default)]
+pub struct ButtonText {
+    /// the text, `{label}` and `{value}` are replaced
+    pub (crate) text:  Option<String>,
+    pub (crate) font:  Option<PathBuf>,
+    pub (crate) size:  Option<f32>,
+    pub (crate) align: Option<TextAlign>,
+    pub (crate) color: Option<ButtonColor>,
+}
+
+
 #[derive(Debug)]
 pub struct ButtonState
 {
@@ -428,17 +806,118 @@ pub struct ButtonState
 
     pub (crate) color: Option<ButtonColor>,
     pub (crate) image: Option<ButtonImage>,
+    // drawn over the image, e.g. a "muted" badge
+    pub (crate) overlays: Option<Vec<ButtonImage>>,
+    pub (crate) effect: Option<Effect>,
 
     pub (crate) value: ButtonValue,
 
+    pub (crate) text: ButtonText,
 
     pub (crate) on_button_down: Option<FnRef>,
     pub (crate) on_button_up:   Option<FnRef>,
+    pub (crate) on_value:       Option<FnRef>,
+
+    pub (crate) on_long_press:    Option<FnRef>,
+    pub (crate) on_double_tap:    Option<FnRef>,
+    pub (crate) long_press_setup: Option<SetupTarget>,
+    pub (crate) repeat:           Option<bool>,
 
     pub (crate) switch_button_state: Option<StateId>,
-    pub (crate) switch_deck_setup: Option<SetupId>,
+    pub (crate) switch_deck_setup: Option<SetupTarget>,
+    // flip the pages of a grid setup
+    pub (crate) page: Option<i32>,
 
 }
 
 
 
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::json;
+
+    fn change(v: &ButtonValue, c: ValueChange) -> Option<f64> {
+        v.changed(c).as_f64()
+    }
+
+    #[test]
+    fn float_range() {
+        let v = ButtonValue::from(json!({ "value": 0.5, "min": -1.0, "max": 1.0, "step": 0.25 }));
+        assert_eq!(change(&v, ValueChange::Unit(0.0)), Some(-1.0));
+        assert_eq!(change(&v, ValueChange::Unit(1.0)), Some(1.0));
+        assert_eq!(change(&v, ValueChange::Unit(1.5)), Some(1.0));
+        assert_eq!(change(&v, ValueChange::Steps(1)), Some(0.75));
+        assert_eq!(change(&v, ValueChange::Steps(3)), Some(1.0));
+        assert_eq!(change(&v, ValueChange::Steps(-10)), Some(-1.0));
+    }
+
+    #[test]
+    fn int_range() {
+        let v = ButtonValue::from(json!({ "value": 50, "min": 0, "max": 100, "step": 2 }));
+        assert!(matches!(v, ButtonValue::IntRange { .. }));
+        assert_eq!(change(&v, ValueChange::Unit(0.0)), Some(0.0));
+        assert_eq!(change(&v, ValueChange::Unit(0.333)), Some(33.0));
+        assert_eq!(change(&v, ValueChange::Unit(1.0)), Some(100.0));
+        assert_eq!(change(&v, ValueChange::Steps(-3)), Some(44.0));
+        assert_eq!(change(&v, ValueChange::Steps(30)), Some(100.0));
+        assert_eq!(change(&v, ValueChange::Steps(i32::MIN)), Some(0.0));
+        assert_eq!(v.changed(ValueChange::Steps(1)).to_string(), "52");
+    }
+
+    #[test]
+    fn range_defaults() {
+        // value defaults to min, step to 1/100 of the range (at least 1 for integers)
+        let v = ButtonValue::from(json!({ "min": 10, "max": 20 }));
+        assert_eq!(v.as_f64(), Some(10.0));
+        assert_eq!(change(&v, ValueChange::Steps(1)), Some(11.0));
+        let v = ButtonValue::from(json!({ "min": 0, "max": 2.0 }));
+        assert!(matches!(v, ButtonValue::Range { .. }));
+        assert_eq!(change(&v, ValueChange::Steps(1)), Some(0.02));
+    }
+
+    #[test]
+    fn widest_int_range() {
+        let v = ButtonValue::from(json!({ "min": i64::MIN, "max": i64::MAX }));
+        assert_eq!(v.as_unit(), Some(0.0));
+        assert!(matches!(v.changed(ValueChange::Unit(1.0)), ButtonValue::IntRange { value: i64::MAX, .. }));
+        assert!(matches!(v.changed(ValueChange::Unit(0.0)), ButtonValue::IntRange { value: i64::MIN, .. }));
+        assert!(matches!(v.changed(ValueChange::Steps(i32::MAX)), ButtonValue::IntRange { .. }));
+    }
+
+    #[test]
+    fn inverted_range() {
+        assert!(matches!(ButtonValue::from(json!({ "min": 10, "max": 0 })), ButtonValue::Error(_)));
+        assert!(matches!(ButtonValue::from(json!({ "min": 1.0, "max": -1.0 })), ButtonValue::Error(_)));
+    }
+
+    #[test]
+    fn plain_numbers() {
+        assert_eq!(change(&ButtonValue::Int(5), ValueChange::Steps(-2)), Some(3.0));
+        assert_eq!(change(&ButtonValue::Int(5), ValueChange::Unit(0.25)), Some(0.25));
+        assert_eq!(change(&ButtonValue::Number(0.5), ValueChange::Steps(1)), Some(1.5));
+    }
+
+    #[test]
+    fn rgba_size() {
+        assert!(ButtonImage::from_rgba(2, 2, vec![0; 16]).is_ok());
+        assert!(ButtonImage::from_rgba(2, 2, vec![0; 12]).is_err());
+        // would overflow in u32
+        assert!(ButtonImage::from_rgba(u32::MAX, u32::MAX, vec![0; 4]).is_err());
+    }
+
+    #[test]
+    fn kept_on_reload() {
+        let range = |value, max| ButtonValue::IntRange { value, min: 0, max, step: 1 };
+        assert_eq!(range(0, 100).kept(&range(40, 127)).as_f64(), Some(40.0));
+        assert_eq!(range(0, 10).kept(&range(40, 127)).as_f64(), Some(10.0));
+        assert_eq!(ButtonValue::Int(1).kept(&ButtonValue::Int(7)).as_f64(), Some(7.0));
+        assert_eq!(ButtonValue::Int(1).kept(&ButtonValue::None).as_f64(), Some(1.0));
+        // not a range any more
+        assert_eq!(ButtonValue::Int(1).kept(&range(40, 127)).as_f64(), Some(1.0));
+    }
+
+}
@@ -1,40 +1,57 @@
 
 use hidapi::HidApi;
 use log::error;
-use log::{debug, warn};
+use log::{debug, info, trace, warn};
 
 use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
 use std::cell::RefCell;
 use std::fmt::Display;
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 
-use crate::button::{ButtonValue, ButtonImage};
+use crate::button::{ButtonValue, ButtonImage, ValueChange};
 use crate::{ButtonId, ButtonColor, ButtonDeckBuilder, DeckId, StateId};
 use crate::Button;
 use crate::DeckError;
-use crate::device::{ButtonDevice, discover_streamdeck};
-use crate::device::PhysicalKey;
+use crate::device::{ButtonDevice, RunningDevice, discover_streamdeck, open_midi};
+use crate::{ButtonDeviceTrait, DeviceFamily};
+use crate::device::{PhysicalKey, KeyKind};
 use crate::device::DeviceEvent;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{Receiver, RecvError, RecvTimeoutError, Sender};
 
-use crate::SetupId;
+use crate::{SetupId, SetupTarget};
+use crate::elog;
+use crate::animation::{Animator, Effect};
+use crate::gesture::{Gesture, GestureTracker};
+use crate::render::{KeyFrame, slice_panel};
+use crate::hardware::DeviceSpecs;
+use crate::watch::ConfigWatcher;
 
 type Result<T> = std::result::Result<T,DeckError>;
 
+// what a key shows: the frame and its effect
+type KeyLook = (KeyFrame, Option<Effect>);
+
+// how long to wait for the device to clear its keys
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(1000);
+
 
 #[derive(Debug)]
 pub enum DeckEvent {
     Void,
     Disconnected,
+    Shutdown,
     Device(DeviceEvent),
     FnCall(String, FnArg),
     SetState(String,String),
     SetImage(String,Option<ButtonImage>),
-    SetValue(String,ButtonValue)
+    SetValue(String,ButtonValue),
+    // one image over all keys, until the next setup switch
+    ShowPanel(ButtonImage)
 }
 
 
@@ -98,7 +115,11 @@ impl <D> ButtonFn<D>
 pub struct ButtonSetup {
     pub (crate) id: SetupId,
     pub (crate) name: String,
-    pub (crate) mapping: Vec<ButtonMapping>
+    pub (crate) mapping: Vec<ButtonMapping>,
+    // one image over all keys, already cut into tiles (key id, tile)
+    pub (crate) panel: Vec<(usize,ButtonImage)>,
+    // controls that flow over the keys, page by page
+    pub (crate) grid: Option<SetupGrid>,
 }
 
 impl Clone for ButtonSetup {
@@ -106,11 +127,56 @@ impl Clone for ButtonSetup {
         ButtonSetup {
             id: self.id.clone(),
             name: self.name.clone(),
-            mapping: self.mapping.iter().map(|b| b.clone()).collect()
+            mapping: self.mapping.iter().map(|b| b.clone()).collect(),
+            panel: self.panel.clone(),
+            grid: self.grid.clone(),
         }
     }
 }
 
+/// The controls of a grid setup and the keys they are spread over.
+/// The page keys (prev and next) are only reserved if the controls
+/// do not fit on the keys.
+#[derive(Clone)]
+pub (crate) struct SetupGrid {
+    pub (crate) controls: Vec<ButtonId>,
+    pub (crate) keys: Vec<PhysicalKey>,
+    pub (crate) page_keys: Option<[(PhysicalKey, ButtonId); 2]>,
+}
+
+impl SetupGrid {
+
+    pub fn pages(&self) -> usize {
+        let per_page = self.keys.len().max(1);
+        self.controls.len().div_ceil(per_page).max(1)
+    }
+
+    /// the mapping of one page and the keys that stay empty
+    pub fn page_mapping(&self, page: usize) -> (Vec<ButtonMapping>, Vec<PhysicalKey>) {
+
+        let start = page * self.keys.len();
+        let mut mapping = Vec::new();
+        let mut unused = Vec::new();
+
+        for (n, key) in self.keys.iter().enumerate() {
+            match self.controls.get(start + n) {
+                Some(b) => mapping.push(ButtonMapping { key: key.clone(), button: *b, state: None }),
+                None => unused.push(key.clone())
+            }
+        }
+
+        for (key, b) in self.page_keys.iter().flatten() {
+            if self.pages() > 1 {
+                mapping.push(ButtonMapping { key: key.clone(), button: *b, state: None });
+            } else {
+                unused.push(key.clone());
+            }
+        }
+
+        (mapping, unused)
+    }
+}
+
 #[derive(Clone)]
 pub struct ButtonMapping {
     pub key:    PhysicalKey,
@@ -119,11 +185,17 @@ pub struct ButtonMapping {
 }
 
 
+#[derive(Clone)]
 pub struct ButtonDeckSender {
+    pub deck: DeckId,
     pub sender: Sender<DeckEvent>
 }
 
 impl ButtonDeckSender {
+
+    pub fn deck_id(&self) -> DeckId {
+        self.deck
+    }
     
     pub fn send(&self, event: DeckEvent) {
         self.sender.send(event);
@@ -140,11 +212,21 @@ impl ButtonDeckSender {
         Ok(())
     }
 
+    /// spread an image over all keys (e.g. album art), buttons are drawn over it
+    pub fn show_panel(&self, image: ButtonImage) -> Result<()> {
+        self.sender.send(DeckEvent::ShowPanel(image)).map_err(|_| DeckError::Disconnected)
+    }
+
     pub fn set_value(&self, button: &str, value: ButtonValue) -> Result<()> {
         self.send(DeckEvent::SetValue(String::from(button), value));
         Ok(())
     }
 
+    /// stop the deck, `ButtonDeck::run` will return
+    pub fn shutdown(&self) -> Result<()> {
+        self.sender.send(DeckEvent::Shutdown).map_err(|_| DeckError::Disconnected)
+    }
+
 }
 
 
@@ -165,6 +247,14 @@ impl FnArg {
             _ => false 
         }
     }
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            FnArg::Int(i) => Some(*i as f64),
+            FnArg::Float(f) => Some(*f as f64),
+            FnArg::Button(_, v) => v.as_f64(),
+            _ => None
+        }
+    }
     pub fn value_to_string(&self) -> String {
         match self {
             FnArg::Button(b, v) => v.to_string(),
@@ -209,6 +299,16 @@ pub struct DeckDeviceSetup {
     // the current, active setup
     pub (crate) current_setup: usize,
 
+    // geometry and capabilities of the device
+    pub (crate) specs: DeviceSpecs,
+
+    // to build the setups again for the running device
+    pub (crate) model: String,
+    pub (crate) serial: Option<String>,
+
+    // panel images of the setups, watched for a hot reload
+    pub (crate) panel_files: Vec<PathBuf>,
+
 
 
 }
@@ -221,7 +321,11 @@ impl Default for DeckDeviceSetup {
             current_key_map: Default::default(), 
             wiring: Default::default(), 
             setup_arena: Default::default() ,
-            current_setup: 0
+            current_setup: 0,
+            specs: Default::default(),
+            model: Default::default(),
+            serial: None,
+            panel_files: Vec::new(),
         }
     }
 }
@@ -257,6 +361,25 @@ pub struct ButtonDeck<D>
 
     pub (crate) ddsetup: DeckDeviceSetup,
 
+    // set by DeckEvent::Shutdown, ends the run loop
+    pub (crate) shutdown: bool,
+
+    // events that arrived while waiting for a device
+    pub (crate) pending: Vec<DeckEvent>,
+
+    pub (crate) gestures: GestureTracker,
+
+    pub (crate) animator: Animator,
+
+    // current page of each grid setup (by setup index)
+    pub (crate) pages: HashMap<usize,usize>,
+
+    // the setups we came from, for SetupTarget::Back
+    pub (crate) history: Vec<SetupId>,
+
+    // set if the config is reloaded on a change
+    pub (crate) watcher: Option<ConfigWatcher>,
+
     pub data: Option<D>,
 
     pub other: Option<Box<dyn Any>>
@@ -354,34 +477,58 @@ impl <D> ButtonDeck<D>
 
     pub fn get_sender(&self) -> ButtonDeckSender {
         ButtonDeckSender {
+            deck: self.id,
             sender: self.deck_event_sender.clone()
         }
     }
 
+    /// run the deck until a `DeckEvent::Shutdown` arrives.
+    /// Reconnects whenever the device is lost.
     pub fn run(&mut self) {
 
 
         let tx_device_to_deck = self.deck_event_sender.clone();
-        let receiver = self.deck_event_receiver.take().expect("it is fatal if we dont have a receiver here");
+        let receiver = match self.deck_event_receiver.take() {
+            Some(r) => r,
+            None => {
+                error!("buttondeck.run: deck is already running");
+                return;
+            }
+        };
         // self.deck_event_sender = tx;
 
+        self.shutdown = false;
+
         loop {
             
             if let Err(e) = self.run_once(&receiver, tx_device_to_deck.clone()) {
                 error!("buttondeck.run error: {:?}", e);
             }
 
-            thread::sleep(Duration::from_millis(3000));
+            if self.shutdown {
+                break;
+            }
+
+            self.wait_or_shutdown(&receiver, Duration::from_millis(3000));
+            if self.shutdown {
+                break;
+            }
         }
 
+        debug!("buttondeck.run finished");
+        self.deck_event_receiver = Some(receiver);
+
     }
 
  
     fn run_once(&mut self, rx: &Receiver<DeckEvent>, tx_device_to_deck: Sender<DeckEvent>) -> Result<()> {
 
 
-        let device = self.run_reconnect();
-        let mut dds = self.builder.build_for_device(device)?;
+        let device = match self.run_reconnect(rx) {
+            Some(d) => d,
+            None => return Ok(())
+        };
+        let mut dds = self.builder.build_for_device(self.id, device)?;
 
 
         let opt_device = dds.device.take();
@@ -394,18 +541,48 @@ impl <D> ButtonDeck<D>
             }
         }
         
+        // the old setups are gone with the old device
+        self.history.clear();
         let nr = self.ddsetup.setup_arena[0].id.clone();
-        self.switch_to_ref(&nr);
+        self.enter_setup(&nr);
+
+        self.watcher = self.watch();
 
 
         self.call_fn_by_name("__connect", FnArg::None);
 
+        for event in std::mem::take(&mut self.pending) {
+            elog!("pending event", self.run_event(event));
+        }
+
         loop {
 
-            match rx.recv() {
+            // wake up for pending long presses, repeats, taps and animations
+            let received = match self.next_deadline() {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    match rx.recv_timeout(left) {
+                        Err(RecvTimeoutError::Timeout) => {
+                            self.tick();
+                            continue;
+                        },
+                        Err(RecvTimeoutError::Disconnected) => Err(RecvError),
+                        Ok(e) => Ok(e)
+                    }
+                },
+                None => rx.recv()
+            };
+
+            match received {
 
+                Ok(DeckEvent::Shutdown) => {
+                    debug!("Shutdown...");
+                    self.shutdown = true;
+                    self.shutdown_device(rx);
+                    break;
+                }
                 Ok(event) => {
-                    debug!("Got event: {:?}", event);
+                    trace!("Got event: {:?}", event);
                     match self.run_event(event) {
                         Ok(_) => (),
                         Err(DeckError::Disconnected) => {
@@ -417,22 +594,67 @@ impl <D> ButtonDeck<D>
                     }
                 }
                 Err(e) => {
-                    error!("event recv error: {:?}", e)
+                    // cannot happen as long as we hold a sender ourselves
+                    error!("event recv error: {:?}", e);
+                    self.shutdown = true;
+                    break;
                 },
             }
+
+            // a steady stream of events (a moving fader, midi clock) never times out
+            if self.next_deadline().map(|d| Instant::now() >= d).unwrap_or(false) {
+                self.tick();
+            }
         }
 
         Ok(())
 
     }
+
+    // tell the device to clear its keys and wait (a little) until it is done
+    fn shutdown_device(&mut self, rx: &Receiver<DeckEvent>) {
+
+        if self.device_event_sender.send(DeviceEvent::Shutdown).is_err() {
+            // device thread is already gone
+            return;
+        }
+
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match rx.recv_timeout(left) {
+                Ok(DeckEvent::Disconnected) => return,
+                Ok(e) => debug!("ignoring event during shutdown: {:?}", e),
+                Err(_) => break
+            }
+        }
+
+        warn!("device did not confirm shutdown");
+    }
+
+    // sleep, but keep listening for a shutdown
+    fn wait_or_shutdown(&mut self, rx: &Receiver<DeckEvent>, timeout: Duration) {
+
+        let deadline = Instant::now() + timeout;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match rx.recv_timeout(left) {
+                Ok(DeckEvent::Shutdown) => {
+                    self.shutdown = true;
+                    return;
+                },
+                Ok(DeckEvent::Disconnected) | Ok(DeckEvent::Device(_)) => {
+                    // stale events of the old device
+                },
+                Ok(e) => self.pending.push(e),
+                Err(_) => return
+            }
+        }
+    }
    
 
 
 
     fn run_event(&mut self, event: DeckEvent) -> Result<()>{
 
-        warn!("DeckEvent ...");
-        
         match event {
 
             DeckEvent::Void => {
@@ -452,12 +674,20 @@ impl <D> ButtonDeck<D>
                 debug!("Disconnected...");
                 return Err(DeckError::Disconnected);
             }
+            DeckEvent::Shutdown => {
+                // handled by run_once
+                self.shutdown = true;
+                return Err(DeckError::Disconnected);
+            }
             DeckEvent::SetImage(name, image) => {
                 self.set_button_icon(&name, "default", image)?;
             },
             DeckEvent::SetValue(name, value) => {
                 self.set_button_value(&name, "default", value)?;
             },
+            DeckEvent::ShowPanel(image) => {
+                self.show_panel(&image)?;
+            },
         }
 
         Ok(())
@@ -467,22 +697,46 @@ impl <D> ButtonDeck<D>
 
 
 
-    fn run_reconnect(&mut self) -> ButtonDevice {
+    // wait for a device, returns None on shutdown
+    fn run_reconnect(&mut self, rx: &Receiver<DeckEvent>) -> Option<ButtonDevice> {
+
+        let kind = self.builder.kind();
+        let family = kind.get_specs().family;
 
         loop {
             debug!("Reconnect Loop...");
+
+            if let Some(vd) = &self.builder.virtual_device {
+                debug!("using virtual device {}", vd.model());
+                return Some(ButtonDevice::Virtual(vd.clone()));
+            }
+
+            let found = match family {
+                DeviceFamily::Streamdeck => {
+                    let serial = self.builder.serial().map(String::from);
+                    let path = self.builder.hid_path().map(String::from);
+                    discover_streamdeck(&mut self.hidapi, serial.as_deref(), path.as_deref())
+                },
+                DeviceFamily::Midi => {
+                    let (midi_in, midi_out) = self.builder.midi_ports();
+                    open_midi(kind, midi_in, midi_out)
+                },
+            };
            
-            match discover_streamdeck(&mut self.hidapi) {
+            match found {
                 Ok(sd) => { 
                     debug!("found device!!! {}", sd.model());
-                    return sd;
+                    return Some(sd);
                 },
                 Err(e) => {
                     warn!("e? {:?}",e)
                 },
             }
 
-            thread::sleep(Duration::from_millis(3000));
+            self.wait_or_shutdown(rx, Duration::from_millis(3000));
+            if self.shutdown {
+                return None;
+            }
         }
 
     }
@@ -498,6 +752,39 @@ impl <D> ButtonDeck<D>
                 self.on_button_up(index);
             }
 
+            DeviceEvent::ButtonValue(index, value) => {
+                elog!(self.on_value(index, ValueChange::Unit(value)));
+            }
+
+            DeviceEvent::Pressure(index, value) => {
+                elog!(self.on_value(index, ValueChange::Unit(value)));
+            }
+
+            DeviceEvent::Turn(index, steps) => {
+                elog!(self.on_value(index, ValueChange::Steps(steps)));
+            }
+
+            DeviceEvent::Touch(index, long) => {
+                // the strip detects long touches itself, they go straight to the long press
+                // of the button on this segment (if it has one), like a held key
+                let long_press = self.button_at_key(index)
+                    .filter(|br| long && self.button(*br).map(|b| b.gesture_bindings().long_press).unwrap_or(false));
+                match long_press {
+                    Some(br) => elog!(self.on_gesture(Gesture::LongPress(br))),
+                    None => {
+                        // a tap is a short press of the button on this segment
+                        elog!(self.on_button_down(index));
+                        elog!(self.on_button_up(index));
+                    }
+                }
+            }
+
+            DeviceEvent::Swipe(from, to) => {
+                // a swipe turns the button on the segment where it started, like a dial:
+                // on_value gets one step per segment, negative to the left
+                elog!(self.on_value(from, ValueChange::Steps(to as i32 - from as i32)));
+            }
+
             _ => {
                 warn!("Unhandled DeviceEvent: {:?}", event)
             }
@@ -520,24 +807,88 @@ impl <D> ButtonDeck<D>
      }
  
      pub fn switch_to_default(&mut self) {
+        self.home();
+    }
 
-        let sref = self.ddsetup.setup_arena.get(0).cloned();
+    /// back to the setup we came from
+    pub fn back(&mut self) {
+        match self.history.pop() {
+            Some(s) => self.enter_setup(&s),
+            None => debug!("back: no previous setup")
+        }
+    }
+
+    /// to the first setup, the way back is forgotten
+    pub fn home(&mut self) {
+
+        self.history.clear();
+
+        let sref = self.ddsetup.setup_arena.get(0).map(|s| s.id);
 
         if let Some(s) = sref {
-            self.switch_to_ref(&s.id);
+            self.enter_setup(&s);
         }
 
     }
+
+    pub fn switch_to_target(&mut self, target: &SetupTarget) {
+        match target {
+            SetupTarget::Setup(s) => self.switch_to_ref(s),
+            SetupTarget::Back => self.back(),
+            SetupTarget::Home => self.home(),
+        }
+    }
  
   
+    /// spread an image over all keys and draw the buttons of the setup over it
+    pub fn show_panel(&mut self, image: &ButtonImage) -> Result<()> {
+
+        let layout = self.ddsetup.specs.key_layout().ok_or(DeckError::NYI("panel images on devices without key grid"))?;
+
+        for (key, tile) in slice_panel(&image.load()?, &layout) {
+            self.device_event_sender.send(DeviceEvent::SetImage(key, tile))?;
+        }
+
+        // the buttons of the setup, grid and page buttons included
+        let bound: Vec<usize> = self.ddsetup.current_key_map.iter().flatten().map(|m| m.key.id).collect();
+        self.redraw_keys(|key, _| bound.contains(&key));
+
+        Ok(())
+    }
+
+    /// switch to a setup and remember the current one for `back`
     pub fn switch_to_ref(&mut self, setup: &SetupId) {
 
+        let current = self.ddsetup.current_setup;
+
+        if setup.index != current {
+            // going to a setup that is on the stack is like going back to it
+            match self.history.iter().position(|s| s.index == setup.index) {
+                Some(pos) => self.history.truncate(pos),
+                None => if let Some(s) = self.ddsetup.setup_arena.get(current) {
+                    self.history.push(s.id);
+                }
+            }
+        }
+
+        self.enter_setup(setup);
+    }
+
+    fn enter_setup(&mut self, setup: &SetupId) {
+
         debug!("switch_to {:?}", setup);
 
+        // pending gestures and animations belong to the buttons of the old setup
+        self.gestures.clear();
+        self.animator.clear();
+
         // cleanup connections to physical buttons
         for b in &mut self.ddsetup.button_arena {
             b.physical = None;
         }
+        for m in &mut self.ddsetup.current_key_map {
+            *m = None;
+        }
 
         if let Some(s) = self.ddsetup.setup_arena.get(setup.index) {
             self.ddsetup.current_setup = setup.index;
@@ -552,18 +903,190 @@ impl <D> ButtonDeck<D>
 
         debug!("init_setup {}", self.ddsetup.current_setup);
 
+        self.bind_setup();
+        self.redraw_keys(|_,_| true);
+
+    }
+
+    // connect the keys to the buttons of the current setup (and page)
+    fn bind_setup(&mut self) {
+
         // FIXME do this without cloning buttonsetup
         if let Some(bs) = self.ddsetup.setup_arena.get(self.ddsetup.current_setup).cloned() {
-            for b in &bs.mapping {
-                self.init_button(b);
+            let mut mapping = bs.mapping;
+            if let Some(grid) = &bs.grid {
+                mapping.extend(grid.page_mapping(self.page()).0);
+            }
+            for b in &mapping {
+                elog!("bind_button", self.bind_button(b));
             }
         }
 
     }
 
-    fn init_button(&mut self, mapping: &ButtonMapping) -> Result<()> {
+    // what each key of the current setup shows: a button, a panel tile or
+    // nothing (unused keys of a grid). Keys that are not in the map are left alone.
+    fn key_looks(&self) -> BTreeMap<usize,KeyLook> {
+
+        let mut looks = BTreeMap::new();
+
+        if let Some(bs) = self.ddsetup.setup_arena.get(self.ddsetup.current_setup) {
+            for (key, tile) in &bs.panel {
+                looks.insert(*key, (KeyFrame { image: Some(tile.clone()), ..KeyFrame::default() }, None));
+            }
+            if let Some(grid) = &bs.grid {
+                for key in grid.page_mapping(self.page()).1 {
+                    looks.entry(key.id)
+                        .or_insert_with(|| (KeyFrame { background: Some(ButtonColor { rgb: 0 }), ..KeyFrame::default() }, None));
+                }
+            }
+        }
+
+        // dials have nothing to show
+        for m in self.ddsetup.current_key_map.iter().flatten().filter(|m| m.key.kind != KeyKind::Dial) {
+            if let Ok(b) = self.button(m.button) {
+                looks.insert(m.key.id, (b.effective_frame(), b.effective_effect().cloned()));
+            }
+        }
+
+        looks
+    }
+
+    // draw the keys of the current setup, if `redraw` says so
+    fn redraw_keys<F>(&mut self, redraw: F)
+        where F: Fn(usize, &KeyLook) -> bool
+    {
+        for (key, look) in self.key_looks() {
+
+            if !redraw(key, &look) {
+                continue;
+            }
+
+            match self.button_at_key(key) {
+                Some(b) => elog!("decorate_button", self.decorate_button(b)),
+                None => {
+                    self.animator.stop(key);
+                    elog!("redraw_keys", self.send_frame(key, look.0));
+                }
+            }
+        }
+    }
+
+    /// build the setups again from the config, the device keeps running.
+    /// Buttons keep their state (by name), only the keys that look different
+    /// or show one of the `changed` files are drawn again.
+    pub fn reload(&mut self, changed: &[PathBuf]) -> Result<()> {
 
-        debug!("init_button {:?} {:?}", mapping.key, mapping.button);
+        let old_looks = self.key_looks();
+
+        let device = RunningDevice::new(&self.ddsetup.model, self.ddsetup.serial.clone(),
+            self.ddsetup.specs.clone(), self.device_event_sender.clone());
+        let mut dds = self.builder.build_for_device(self.id, ButtonDevice::Running(device))?;
+        if let Some(ButtonDevice::Running(rd)) = dds.device.take() {
+            rd.apply();
+        }
+
+        // the states and values of the old buttons, where the button and the state still exist
+        for b in &mut dds.button_arena {
+            let old = match self.ddsetup.button_arena.iter().find(|o| o.name == b.name) {
+                Some(o) => o,
+                None => continue
+            };
+            for s in &mut b.states {
+                if let Some(os) = old.states.iter().find(|os| os.name == s.name) {
+                    s.value = s.value.kept(&os.value);
+                }
+            }
+            if let Some(s) = b.get_state_id(&old.current_state().name) {
+                b.switch_state2(s);
+            }
+        }
+
+        let old = std::mem::replace(&mut self.ddsetup, dds);
+
+        // setups by name: the current one, the way back and the pages
+        let remap: Vec<Option<usize>> = old.setup_arena.iter()
+            .map(|s| self.ddsetup.setup_arena.iter().position(|n| n.name == s.name))
+            .collect();
+        let index_of = |i: usize| remap.get(i).copied().flatten();
+
+        self.ddsetup.current_setup = index_of(old.current_setup).unwrap_or(0);
+        self.history = self.history.iter()
+            .filter_map(|s| index_of(s.index))
+            .filter_map(|i| self.ddsetup.setup_arena.get(i).map(|s| s.id))
+            .collect();
+        self.pages = self.pages.iter()
+            .filter_map(|(s,p)| index_of(*s).map(|i| (i, *p)))
+            .collect();
+
+        // page numbers may be out of range now
+        if let Some(grid) = self.ddsetup.setup_arena.get(self.ddsetup.current_setup).and_then(|s| s.grid.as_ref()) {
+            let pages = grid.pages();
+            if let Some(p) = self.pages.get_mut(&self.ddsetup.current_setup) {
+                *p = (*p).min(pages - 1);
+            }
+        }
+
+        // button ids may have changed
+        self.gestures.clear();
+        self.animator.clear();
+
+        self.bind_setup();
+
+        let shows_changed = |look: &KeyLook| look.0.image.iter().chain(look.0.overlays.iter())
+            .filter_map(|i| i.path())
+            .any(|p| changed.iter().any(|c| c == p));
+
+        // effects are started again, the animator forgot them
+        self.redraw_keys(|key, look| {
+            old_looks.get(&key) != Some(look) || look.1.is_some() || shows_changed(look)
+        });
+
+        // keys that showed something and are not used any more
+        let looks = self.key_looks();
+        for key in old_looks.keys().filter(|k| !looks.contains_key(k)) {
+            elog!("blank key", self.device_event_sender.send(DeviceEvent::SetColor(*key, ButtonColor { rgb: 0 })));
+        }
+
+        // the includes and files of the new config
+        self.watcher = self.watch();
+
+        info!("config reloaded");
+        Ok(())
+    }
+
+    /// the page of the current grid setup
+    pub fn page(&self) -> usize {
+        self.pages.get(&self.ddsetup.current_setup).copied().unwrap_or(0)
+    }
+
+    /// flip the pages of the current grid setup by `step`, wraps around at both ends
+    pub fn page_by(&mut self, step: i32) {
+
+        let setup = match self.ddsetup.setup_arena.get(self.ddsetup.current_setup) {
+            Some(s) => s,
+            None => return
+        };
+
+        let pages = match &setup.grid {
+            Some(g) => g.pages(),
+            None => {
+                warn!("setup {} is not a grid", setup.name);
+                return;
+            }
+        };
+
+        let id = setup.id;
+        let page = (self.page() as i64 + step as i64).rem_euclid(pages as i64) as usize;
+        debug!("page {} of {}", page + 1, pages);
+
+        self.pages.insert(self.ddsetup.current_setup, page);
+        self.enter_setup(&id);
+    }
+
+    fn bind_button(&mut self, mapping: &ButtonMapping) -> Result<()> {
+
+        debug!("bind_button {:?} {:?}", mapping.key, mapping.button);
 
         self.ddsetup.current_key_map[mapping.key.id] = Some(mapping.clone());
         {
@@ -578,35 +1101,71 @@ impl <D> ButtonDeck<D>
             }
         }
 
-        self.decorate_button(mapping.button)
+        Ok(())
 
     }
 
 
 
-    fn decorate_button(&self, btn: ButtonId) -> Result<()> {
+    fn decorate_button(&mut self, btn: ButtonId) -> Result<()> {
 
         debug!("decorate_button {:?}", &btn);
 
         let button = self.button(btn)?;
-        let color = button.effective_color();
-        let image = button.effective_image();
+        let frame = button.effective_frame();
+        let effect = button.effective_effect().cloned();
 
-        let key = button.assigned_key();
+        let key = button.assigned_key().filter(|k| k.kind != KeyKind::Dial);
         if let Some(pk) = key {
             debug!("key is {:?}", &pk);
-            if let Some(c) = color {
-                self.device_event_sender.send(DeviceEvent::SetColor(pk.id, c.clone()))?;
+
+            // a new state (or setup) ends the animation of the old one
+            match &effect {
+                Some(e) => self.animator.start(pk.id, btn, e, frame.image.as_ref(), Instant::now()),
+                None => self.animator.stop(pk.id)
             }
-            if let Some(c) = image {
-                debug!("image is {:?}", &c);
-                self.device_event_sender.send(DeviceEvent::SetImage(pk.id, c.clone()))?;
+
+            self.send_frame(pk.id, frame)?;
+        }
+
+        Ok(())
+    }
+
+    fn send_frame(&self, key: usize, frame: KeyFrame) -> Result<()> {
+
+        if frame.is_color() {
+            // plain colors are dimmed here, no need to render anything
+            if let Some(c) = &frame.background {
+                self.device_event_sender.send(DeviceEvent::SetColor(key, c.dimmed(frame.brightness)))?;
             }
+            return Ok(());
+        }
+
+        if !frame.is_simple() {
+            // several layers, the device composes them into one image
+            self.device_event_sender.send(DeviceEvent::SetFrame(key, frame))?;
+            return Ok(());
+        }
+
+        if let Some(c) = &frame.background {
+            self.device_event_sender.send(DeviceEvent::SetColor(key, c.clone()))?;
+        }
+        if let Some(c) = &frame.image {
+            debug!("image is {:?}", &c);
+            self.device_event_sender.send(DeviceEvent::SetImage(key, c.clone()))?;
         }
 
         Ok(())
     }
 
+    // keys with a label may show the value, keys without one stay as they are
+    fn redraw_label(&mut self, btn: ButtonId) -> Result<()> {
+        if self.button(btn)?.effective_label().is_some() {
+            self.decorate_button(btn)?;
+        }
+        Ok(())
+    }
+
     pub fn toggle_button_state(&mut self, rb: ButtonId) -> Result<()> {
         let b = self.button_mut(rb)?;
         if b.toggle_state() {
@@ -676,6 +1235,7 @@ impl <D> ButtonDeck<D>
 
         }
 
+        self.redraw_label(bid)?;
         Ok(())
 
     }
@@ -727,6 +1287,16 @@ impl <D> ButtonDeck<D>
 
     }
 
+    fn call_fn_with_arg(&mut self, fr: &FnRef, arg: FnArg) {
+
+        let opt_func = self.functions.get(fr.id).cloned();
+
+        if let Some(f) = opt_func {
+            f.1.borrow_mut().call_fn(self,arg);
+        }
+
+    }
+
     pub fn button_id_from_name(&self, bname: &str) -> Result<ButtonId> {
         // self.button_map.get(button).cloned()
         self.ddsetup.button_arena.iter().enumerate()
@@ -765,7 +1335,19 @@ impl <D> ButtonDeck<D>
 
 
 
+    pub fn id(&self) -> DeckId {
+        self.id
+    }
+
+    /// geometry and capabilities of the connected device
+    pub fn specs(&self) -> &DeviceSpecs {
+        &self.ddsetup.specs
+    }
+
     fn button(&self, id: ButtonId) -> Result<&Button> {
+        if id.deck != self.id {
+            return Err(DeckError::InvalidRef);
+        }
         self.ddsetup.button_arena
             .get(id.index)
             .ok_or(DeckError::InvalidRef)
@@ -773,6 +1355,9 @@ impl <D> ButtonDeck<D>
 
     fn button_mut(&mut self, id: ButtonId) -> Result<&mut Button> {
 
+        if id.deck != self.id {
+            return Err(DeckError::InvalidRef);
+        }
 
         self.ddsetup.button_arena
             .get_mut(id.index)
@@ -784,81 +1369,195 @@ impl <D> ButtonDeck<D>
 
         debug!("on_button_down #{}", index);
         
-        let btn = match self.ddsetup.current_key_map.get(index) {
-            Some(om) => match om {
-                Some(m) => Some(m.button.clone()),
-                None => None
-            }
-            None => None
-        };
-        
-        
-        if let Some(br) = btn {
+        if let Some(br) = self.button_at_key(index) {
 
             debug!("on_button_down id={:?}", br);
 
-            let opt_fr = self.button(br)?.effective_button_down().cloned();
-            
-            if let Some(fr) = opt_fr {
-                debug!("a");
-                self.call_fn(&fr, br);
-            }
+            let bindings = self.button(br)?.gesture_bindings();
+            let gesture = self.gestures.press(index, br, bindings, Instant::now());
 
-            debug!("aa");
-            
-            let switched = self.button_mut(br)?.switch_state_action();
-            debug!("ab");
-            if switched {
-                debug!("b");
-                self.decorate_button(br)?;
+            if let Some(g) = gesture {
+                self.on_gesture(g)?;
+            } else if !bindings.deferred() {
+                self.button_down_actions(br)?;
             }
 
-            if let Some(s) = self.button_mut(br)?.effective_switch_deck_setup().cloned() {
-                debug!("c");
+        }
 
-                self.switch_to_ref(&s);
-            }
+       
+        Ok(())
+
+    }
 
+    // on_down, state switching and setup switching of a button
+    fn button_down_actions(&mut self, br: ButtonId) -> Result<()> {
+
+        let opt_fr = self.button(br)?.effective_button_down().cloned();
+        
+        if let Some(fr) = opt_fr {
+            self.call_fn(&fr, br);
+        }
+
+        let switched = self.button_mut(br)?.switch_state_action();
+        if switched {
+            self.decorate_button(br)?;
+        }
+
+        if let Some(s) = self.button_mut(br)?.effective_switch_deck_setup().cloned() {
+            self.switch_to_target(&s);
+        }
+
+        if let Some(step) = self.button(br)?.effective_page() {
+            self.page_by(step);
         }
 
-       
         Ok(())
+    }
+
+    fn button_up_actions(&mut self, br: ButtonId) -> Result<()> {
+
+        let opt_fr = self.button(br)?.effective_button_up().cloned();
+        
+        if let Some(fr) = opt_fr {
+            self.call_fn(&fr, br);
+        }
 
+        Ok(())
     }
 
-    fn on_button_up(&mut self, index: usize) -> Result<()> {
+    fn on_gesture(&mut self, gesture: Gesture) -> Result<()> {
 
-        debug!("on_button_up #{}", index);
+        debug!("on_gesture {:?}", gesture);
 
-        let btn = match self.ddsetup.current_key_map.get(index) {
-            Some(om) => match om {
-                Some(m) => Some(m.button.clone()),
-                None => None
-            }
-            None => None
+        match gesture {
+            Gesture::Tap(br) => {
+                // a deferred press turned out to be a simple tap
+                self.button_down_actions(br)?;
+                self.button_up_actions(br)?;
+            },
+            Gesture::DoubleTap(br) => {
+                if let Some(fr) = self.button(br)?.effective_on_double_tap().cloned() {
+                    self.call_fn(&fr, br);
+                }
+            },
+            Gesture::LongPress(br) => {
+                if let Some(fr) = self.button(br)?.effective_on_long_press().cloned() {
+                    self.call_fn(&fr, br);
+                }
+                if let Some(s) = self.button(br)?.effective_long_press_setup().cloned() {
+                    self.switch_to_target(&s);
+                }
+            },
+            Gesture::Repeat(br) => {
+                if let Some(fr) = self.button(br)?.effective_button_down().cloned() {
+                    self.call_fn(&fr, br);
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    // everything that is due at the next deadline
+    fn tick(&mut self) {
+        self.gesture_tick();
+        self.animation_tick();
+        self.watch_tick();
+    }
+
+    // long presses, repeats and single taps that are due
+    fn gesture_tick(&mut self) {
+        for g in self.gestures.tick(Instant::now()) {
+            elog!("gesture", self.on_gesture(g));
+        }
+    }
+
+    // the next frames of blinking and animated keys
+    fn animation_tick(&mut self) {
+
+        let arena = &self.ddsetup.button_arena;
+        let frames = self.animator.tick(Instant::now(), |br| {
+            arena.get(br.index).map(|b| b.effective_frame())
+        });
+
+        for (key, frame) in frames {
+            elog!("animation", self.send_frame(key, frame));
+        }
+    }
+
+    // a watcher for the config, its includes and the files the controls use
+    fn watch(&self) -> Option<ConfigWatcher> {
+        let mut paths = self.builder.watch_paths()?;
+        paths.extend(self.ddsetup.button_arena.iter().flat_map(|b| b.files()).map(PathBuf::from));
+        paths.extend(self.ddsetup.panel_files.iter().cloned());
+        paths.sort();
+        paths.dedup();
+        Some(ConfigWatcher::new(paths))
+    }
+
+    // reload the config if it (or an asset) changed
+    fn watch_tick(&mut self) {
+        let changed = match &mut self.watcher {
+            Some(w) => w.poll(Instant::now()),
+            None => return
         };
+        if !changed.is_empty() {
+            info!("config changed: {:?}", changed);
+            if let Err(e) = self.reload(&changed) {
+                error!("config reload failed, the old setups stay active: {}", e);
+            }
+        }
+    }
 
+    // the next wakeup for gestures, animations or the config watcher
+    fn next_deadline(&self) -> Option<Instant> {
+        [
+            self.gestures.next_deadline(),
+            self.animator.next_deadline(),
+            self.watcher.as_ref().and_then(|w| w.next_deadline()),
+        ].into_iter().flatten().min()
+    }
 
-        if let Some(br) = btn {
+    fn button_at_key(&self, index: usize) -> Option<ButtonId> {
+        match self.ddsetup.current_key_map.get(index) {
+            Some(Some(m)) => Some(m.button),
+            _ => None
+        }
+    }
+
+    // faders, dials, pressure: update the value and call on_value with it
+    fn on_value(&mut self, index: usize, change: ValueChange) -> Result<()> {
+
+        debug!("on_value #{} {:?}", index, change);
+
+        if let Some(br) = self.button_at_key(index) {
+
+            let value = self.button_mut(br)?.change_value(change);
+            self.redraw_label(br)?;
+            let opt_fr = self.button(br)?.effective_on_value().cloned();
 
-            let opt_fr = self.button(br)?.effective_button_up().cloned();
-            
             if let Some(fr) = opt_fr {
-                self.call_fn(&fr, br);
+                self.call_fn_with_arg(&fr, FnArg::Button(br, value));
             }
         }
 
-            // if let Some(fr) = self.button(&br)?.effective_button_up() {
-            //     if let Some(func) = self.functions.get(fr.id) {
-            //         func.call_fn(self,&br);
-            //     }
-            // }
-        // }
-        // if let Some(br) = btn {
-        //     if let Some(bdf) = self.button(&br)?.effective_button_up() {
-        //         bdf.call_fn(self,&br);
-        //     }
-        // }
+        Ok(())
+    }
+
+    fn on_button_up(&mut self, index: usize) -> Result<()> {
+
+        debug!("on_button_up #{}", index);
+
+        if let Some(br) = self.button_at_key(index) {
+
+            let deferred = self.button(br)?.gesture_bindings().deferred();
+
+            match self.gestures.release(index, Instant::now()) {
+                Some(g) => self.on_gesture(g)?,
+                None if !deferred => self.button_up_actions(br)?,
+                None => ()
+            }
+        }
 
         Ok(())
     }
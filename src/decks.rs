@@ -0,0 +1,144 @@
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use log::error;
+
+use crate::{ButtonDeckBuilder, ButtonDeckSender, ButtonId, DeckError, DeckEvent, DeckId, elog};
+
+type Result<T> = std::result::Result<T,DeckError>;
+
+
+/// A running buttondeck in its own thread
+pub struct DeckHandle {
+    pub id: DeckId,
+    pub name: String,
+    sender: ButtonDeckSender,
+    thread: JoinHandle<()>,
+}
+
+impl DeckHandle {
+
+    pub fn sender(&self) -> &ButtonDeckSender {
+        &self.sender
+    }
+
+    /// stop the deck, use `join` to wait for it
+    pub fn shutdown(&self) -> Result<()> {
+        self.sender.shutdown()
+    }
+
+    pub fn join(self) {
+        if let Err(e) = self.thread.join() {
+            error!("deck thread {} paniced: {:?}", self.name, e);
+        }
+    }
+
+}
+
+
+/// Several buttondecks (e.g. a Stream Deck XL and a Mini) in one process.
+///
+/// Each deck runs in its own thread and is bound to its device by the builder
+/// (`with_serial`, `with_midi_in`), events are routed by [`DeckId`].
+pub struct ButtonDecks {
+    decks: Vec<DeckHandle>
+}
+
+impl ButtonDecks {
+
+    pub fn new() -> Self {
+        ButtonDecks {
+            decks: Vec::new()
+        }
+    }
+
+    /// build the deck and run it in a new thread
+    pub fn spawn<D>(&mut self, name: &str, builder: ButtonDeckBuilder<D>) -> Result<DeckId>
+        where D: Send + Sync + 'static
+    {
+
+        let (tx,rx) = mpsc::channel();
+
+        let thread = thread::spawn(move || {
+            match builder.build() {
+                Ok(mut buttondeck) => {
+                    if tx.send(Ok(buttondeck.get_sender())).is_ok() {
+                        buttondeck.run()
+                    }
+                },
+                Err(e) => {
+                    // DeckError is not Send (midir errors), so pass on the message
+                    elog!(tx.send(Err(format!("{:?}", e))));
+                }
+            }
+        });
+
+        let sender = rx.recv()
+            .map_err(|_| DeckError::Message(format!("deck thread {} died", name)))?
+            .map_err(|e| DeckError::Message(format!("cannot build deck {}: {}", name, e)))?;
+
+        let id = sender.deck_id();
+
+        self.decks.push(DeckHandle {
+            id,
+            name: String::from(name),
+            sender,
+            thread
+        });
+
+        Ok(id)
+    }
+
+    pub fn ids(&self) -> Vec<DeckId> {
+        self.decks.iter().map(|d| d.id).collect()
+    }
+
+    pub fn deck(&self, id: DeckId) -> Option<&DeckHandle> {
+        self.decks.iter().find(|d| d.id == id)
+    }
+
+    pub fn deck_by_name(&self, name: &str) -> Option<&DeckHandle> {
+        self.decks.iter().find(|d| d.name == name)
+    }
+
+    pub fn sender(&self, id: DeckId) -> Option<&ButtonDeckSender> {
+        self.deck(id).map(|d| &d.sender)
+    }
+
+    /// the sender of the deck that owns this button
+    pub fn sender_for_button(&self, button: ButtonId) -> Option<&ButtonDeckSender> {
+        self.sender(button.deck())
+    }
+
+    /// send an event to one deck
+    pub fn send(&self, id: DeckId, event: DeckEvent) -> Result<()> {
+        match self.sender(id) {
+            Some(s) => s.sender.send(event).map_err(|_| DeckError::Disconnected),
+            None => Err(DeckError::InvalidRef)
+        }
+    }
+
+    /// stop all decks and wait for them
+    pub fn shutdown(self) {
+        for d in &self.decks {
+            if let Err(e) = d.shutdown() {
+                error!("shutdown {}: {:?}", d.name, e);
+            }
+        }
+        self.join();
+    }
+
+    /// wait for all deck threads
+    pub fn join(self) {
+        for d in self.decks {
+            d.join();
+        }
+    }
+
+}
+
+impl Default for ButtonDecks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
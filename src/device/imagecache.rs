@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+use image::DynamicImage;
+use image::imageops::FilterType;
+use log::debug;
+
+use crate::DeckError;
+
+type Result<T> = std::result::Result<T,DeckError>;
+
+
+// image files, already resized and encoded for one kind of device.
+// Shared by all decks of the process, it is filled while the config
+// is loaded and used by the device threads.
+static CACHE: OnceLock<Mutex<ImageCache>> = OnceLock::new();
+
+// the least recently used images are dropped above this size
+const MAX_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+
+#[derive(Default)]
+struct ImageCache {
+    entries: HashMap<CacheKey,CacheEntry>,
+    bytes: usize,
+    // counts the lookups, the age of the entries
+    tick: u64,
+}
+
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    path: PathBuf,
+    format: &'static str,
+    size: (u32,u32),
+}
+
+struct CacheEntry {
+    // the file is converted again if it changed
+    modified: Option<SystemTime>,
+    data: Arc<Vec<u8>>,
+    used: u64,
+}
+
+
+impl ImageCache {
+
+    fn get(&mut self, key: &CacheKey, modified: Option<SystemTime>) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let e = self.entries.get_mut(key).filter(|e| e.modified == modified)?;
+        e.used = self.tick;
+        Some(e.data.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, modified: Option<SystemTime>, data: Arc<Vec<u8>>) {
+        self.tick += 1;
+        self.bytes += data.len();
+        let entry = CacheEntry { modified, data, used: self.tick };
+        if let Some(old) = self.entries.insert(key, entry) {
+            self.bytes -= old.data.len();
+        }
+        while self.bytes > MAX_CACHE_BYTES && self.entries.len() > 1 {
+            let oldest = self.entries.iter()
+                .min_by_key(|(_,e)| e.used)
+                .map(|(k,_)| k.clone());
+            if let Some(e) = oldest.and_then(|k| self.entries.remove(&k)) {
+                self.bytes -= e.data.len();
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+}
+
+
+fn cache() -> &'static Mutex<ImageCache> {
+    CACHE.get_or_init(|| Mutex::new(ImageCache::default()))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+
+/// The image file in the native `format` of a device. `encode` gets the image
+/// scaled to `size` and returns what the device wants (e.g. a rotated jpeg).
+/// Devices with the same `format` must encode the same way.
+pub fn native_image<F>(path: &Path, format: &'static str, size: (u32,u32), encode: F) -> Result<Arc<Vec<u8>>>
+    where F: FnOnce(DynamicImage) -> Result<Vec<u8>>
+{
+
+    let key = CacheKey {
+        path: path.to_path_buf(),
+        format,
+        size
+    };
+    let mtime = modified(path);
+
+    if let Ok(mut c) = cache().lock() {
+        if let Some(data) = c.get(&key, mtime) {
+            return Ok(data);
+        }
+    }
+
+    debug!("image cache miss: {:?} for {} {:?}", path, format, size);
+
+    let img = image::open(path)?
+        .resize_to_fill(size.0, size.1, FilterType::Triangle);
+    let data = Arc::new(encode(img)?);
+
+    if let Ok(mut c) = cache().lock() {
+        c.insert(key, mtime, data.clone());
+    }
+
+    Ok(data)
+}
+
+
+/// drop all cached images (of all devices)
+pub fn clear_image_cache() {
+    if let Ok(mut c) = cache().lock() {
+        c.clear();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn formats_are_cached_apart() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("demo").join("right.png");
+        let bmp = native_image(&path, "test_bmp", (8,8), |_| Ok(vec![1])).unwrap();
+        let jpeg = native_image(&path, "test_jpeg", (8,8), |_| Ok(vec![2])).unwrap();
+        assert_eq!((bmp.as_slice(), jpeg.as_slice()), (&[1u8][..], &[2u8][..]));
+        // the second time it comes from the cache
+        let again = native_image(&path, "test_bmp", (8,8), |_| panic!("not cached")).unwrap();
+        assert_eq!(again.as_slice(), &[1u8][..]);
+    }
+
+    #[test]
+    fn least_recently_used_are_dropped() {
+        let key = |name: &str| CacheKey { path: PathBuf::from(name), format: "test", size: (8,8) };
+        let mut cache = ImageCache::default();
+        cache.insert(key("a"), None, Arc::new(vec![1]));
+        cache.insert(key("b"), None, Arc::new(vec![2]));
+        assert!(cache.get(&key("a"), None).is_some());
+        cache.insert(key("c"), None, Arc::new(vec![0; MAX_CACHE_BYTES - 1]));
+        assert!(cache.get(&key("a"), None).is_some());
+        assert!(cache.get(&key("b"), None).is_none());
+        assert_eq!(cache.bytes, MAX_CACHE_BYTES);
+    }
+
+}
@@ -1,18 +1,27 @@
-use std::{sync::mpsc::{self, Receiver, Sender}, time::Duration, thread};
+use std::{sync::mpsc::{self, Receiver, Sender, TryRecvError}, time::{Duration, Instant}, thread};
 
-use log::{error, info, debug, trace};
+use log::{error, info, debug, trace, warn};
 use midir::{MidiInput, MidiOutput, Ignore};
 use wmidi::{MidiMessage, Channel, Note, Velocity, ControlFunction, ControlValue, ProgramNumber, PitchBend};
 
-use crate::{DeviceKind,ButtonDeviceTrait, DeckError, Button, DeckEvent};
+use crate::{DeviceKind,ButtonDeviceTrait, DeckError, Button, ButtonColor, DeckEvent, MidiFeedback, elog};
 
-use super::{DeviceEvent, ButtonDevice};
+use super::{DeviceEvent, ButtonDevice, PhysicalKey, KeyKind, MIDI_CC_BASE};
+use crate::hardware::{DeviceSpecs, DiscoveredDevice};
 
 
 
 
 type Result<T> = std::result::Result<T,DeckError>;
 
+const PORT_CHECK_INTERVAL: Duration = Duration::from_millis(1000);
+
+// the 4x16 pads of the akai fire are notes 0x36 .. 0x75
+const FIRE_FIRST_PAD: u8 = 0x36;
+
+// (off,on) for cckeys without on_off_threshold
+const DEFAULT_CC_THRESHOLD: (u8,u8) = (63,64);
+
 
 // wmidi MidiMessage has a Lifetime Specifier, so we can not send it over a 
 // mpsc::channel, therefore here is a sendable selection of midi messages 
@@ -59,10 +68,10 @@ impl From<MidiMessage<'_>> for SendMidi {
             MidiMessage::NoteOn(c, n, v) => SendMidi::NoteOn(c,n,v),
             MidiMessage::PolyphonicKeyPressure(c, n, v) => SendMidi::PolyphonicKeyPressure(c,n,v),
             MidiMessage::ControlChange(c,f,v) => SendMidi::ControlChange(c,f,v),
+            MidiMessage::ProgramChange(c, p) => SendMidi::ProgramChange(c,p),
+            MidiMessage::ChannelPressure(c, v) => SendMidi::ChannelPressure(c,v),
+            MidiMessage::PitchBendChange(c, p) => SendMidi::PitchBendChange(c,p),
 /*
-            MidiMessage::ProgramChange(_, _) => todo!(),
-            MidiMessage::ChannelPressure(_, _) => todo!(),
-            MidiMessage::PitchBendChange(_, _) => todo!(),
             MidiMessage::SysEx(_) => todo!(),
             MidiMessage::OwnedSysEx(_) => todo!(),
             MidiMessage::MidiTimeCode(_) => todo!(),
@@ -92,12 +101,50 @@ pub struct MidiDevice {
     // btn_state: [u8;256],
     // btn_names: [Option<ButtonName>;256],
     model: String,
+    kind: DeviceKind,
+
+    // port name, used to detect a disconnect
+    in_port_name: String,
+
+    keymap: MidiKeyMap,
+    feedback: MidiFeedback,
 
     midi_out: midir::MidiOutputConnection,
     midi_in: midir::MidiInputConnection<()>,
 }
 
 impl MidiDevice {
+
+    // all leds of the wired keys off. Other note or controller numbers are
+    // left alone, e.g. CC 120..127 are channel mode messages (all notes off, ...)
+    fn clear(&mut self) {
+        let black = ButtonColor { rgb: 0 };
+        for k in self.keymap.leds.clone() {
+            self.send_color(k, &black);
+        }
+    }
+
+    // light the key with the given color, according to the feedback of the device
+    fn send_color(&mut self, index: usize, color: &ButtonColor) {
+
+        let (key, is_cc) = match index {
+            i if i < MIDI_CC_BASE => (i as u8, false),
+            i if i < MIDI_CC_BASE + 128 => ((i - MIDI_CC_BASE) as u8, true),
+            _ => return
+        };
+
+        let message = match feedback_message(self.feedback, key, is_cc, color) {
+            Some(m) => m,
+            None => return
+        };
+
+        trace!("midi out: {:02x?}", message);
+        if let Err(e) = self.midi_out.send(&message) {
+            error!("midi send error: {:?}", e);
+        }
+
+    }
+
 /* 
     fn wait_for_events(&mut self, timeout: usize) -> Result<Vec<super::DeviceEvent>> {
 
@@ -128,12 +175,174 @@ impl MidiDevice {
 }
 
 
+
+// translates incoming midi messages to device events, 
+// according to the key kinds from the wiring
+// (indexed by controller number, not by key id)
+struct MidiKeyMap {
+    is_cc:        [bool;128],
+    cc_kind:      [KeyKind;128],
+    cc_threshold: [(u8,u8);128],
+    cc_down:      [bool;128],
+    pressure_key:  Option<usize>,
+    pitchbend_key: Option<usize>,
+    // key ids of the wired notes and controllers
+    leds: Vec<usize>,
+}
+
+impl Default for MidiKeyMap {
+    fn default() -> Self {
+        MidiKeyMap {
+            is_cc: [false;128],
+            // unwired controllers send values
+            cc_kind: [KeyKind::CcValue;128],
+            cc_threshold: [DEFAULT_CC_THRESHOLD;128],
+            cc_down: [false;128],
+            pressure_key: None,
+            pitchbend_key: None,
+            leds: Vec::new(),
+        }
+    }
+}
+
+impl MidiKeyMap {
+
+    fn from_wiring(wiring: &[Option<PhysicalKey>]) -> Self {
+
+        let mut km = MidiKeyMap::default();
+
+        for pk in wiring.iter().flatten() {
+            match pk.kind {
+                KeyKind::CcKey | KeyKind::CcValue => {
+                    let cc = pk.id.wrapping_sub(MIDI_CC_BASE);
+                    if cc < 128 {
+                        km.is_cc[cc] = true;
+                        km.cc_kind[cc] = pk.kind;
+                        km.cc_threshold[cc] = pk.on_off_threshold.unwrap_or(DEFAULT_CC_THRESHOLD);
+                        km.leds.push(pk.id);
+                    } else {
+                        warn!("controller key {} out of midi range", pk.name);
+                    }
+                },
+                KeyKind::Pressure => km.pressure_key = Some(pk.id),
+                KeyKind::PitchBend => km.pitchbend_key = Some(pk.id),
+                KeyKind::Key if pk.id < 128 => km.leds.push(pk.id),
+                KeyKind::Key | KeyKind::Dial | KeyKind::Touch => {}
+            }
+        }
+
+        km
+    }
+
+    fn translate(&mut self, m: SendMidi) -> Option<DeviceEvent> {
+
+        match m {
+            SendMidi::NoteOn(_, n, v) => {
+                let velocity = u8::from(v);
+                if velocity == 0 {
+                    // running status devices send NoteOn/0 instead of NoteOff
+                    Some(DeviceEvent::ButtonUp(n as usize))
+                } else {
+                    Some(DeviceEvent::ButtonDown(n as usize, unit7(velocity)))
+                }
+            },
+            SendMidi::NoteOff(_, n, _) => {
+                Some(DeviceEvent::ButtonUp(n as usize))
+            },
+            SendMidi::PolyphonicKeyPressure(_, n, v) => {
+                Some(DeviceEvent::Pressure(n as usize, unit7(u8::from(v))))
+            },
+            SendMidi::ControlChange(_, f, v) => {
+                let cc = u8::from(f.0) as usize;
+                let value = u8::from(v);
+                match self.cc_kind[cc] {
+                    KeyKind::CcKey => self.cc_key(cc, value),
+                    _ => Some(DeviceEvent::ButtonValue(MIDI_CC_BASE + cc, unit7(value)))
+                }
+            },
+            SendMidi::ChannelPressure(_, v) => {
+                self.pressure_key.map(|k| DeviceEvent::Pressure(k, unit7(u8::from(v))))
+            },
+            SendMidi::PitchBendChange(_, p) => {
+                let value = u16::from(p) as f32 / 16383.0;
+                self.pitchbend_key.map(|k| DeviceEvent::ButtonValue(k, value))
+            },
+            SendMidi::ProgramChange(_, _) => None,
+            SendMidi::Other(_) => None,
+        }
+
+    }
+
+    // a controller used as key, with hysteresis between off and on
+    fn cc_key(&mut self, cc: usize, value: u8) -> Option<DeviceEvent> {
+        let (off, on) = self.cc_threshold[cc];
+        if !self.cc_down[cc] && value >= on {
+            self.cc_down[cc] = true;
+            Some(DeviceEvent::ButtonDown(MIDI_CC_BASE + cc, unit7(value)))
+        } else if self.cc_down[cc] && value <= off {
+            self.cc_down[cc] = false;
+            Some(DeviceEvent::ButtonUp(MIDI_CC_BASE + cc))
+        } else {
+            None
+        }
+    }
+
+}
+
+fn unit7(v: u8) -> f32 {
+    v as f32 / 127.0
+}
+
+// the midi message that shows the color on key (a note or a controller)
+fn feedback_message(feedback: MidiFeedback, key: u8, is_cc: bool, color: &ButtonColor) -> Option<Vec<u8>> {
+
+    let (r, g, b) = color.to_rgb7();
+    let level = r.max(g).max(b);
+
+    match feedback {
+        MidiFeedback::None => None,
+        MidiFeedback::NoteVelocity => {
+            if is_cc {
+                Some(vec![ 0xB0, key, level ])
+            } else {
+                Some(vec![ 0x90, key, level ])
+            }
+        },
+        MidiFeedback::CcLed => {
+            Some(vec![ 0xB0, key, if level > 0 { 0x7f } else { 0x00 } ])
+        },
+        MidiFeedback::AkaiFire => {
+            if (FIRE_FIRST_PAD..FIRE_FIRST_PAD + 64).contains(&key) {
+                // F0 47 7F 43 65 <len hi> <len lo> <pad> <r> <g> <b> F7
+                Some(vec![ 0xF0, 0x47, 0x7F, 0x43, 0x65, 0x00, 0x04, key - FIRE_FIRST_PAD, r, g, b, 0xF7 ])
+            } else {
+                // single color buttons have led levels 0 (off), 1 (dim) and 2 (bright)
+                let fire_level = if level > 0x40 { 2 } else if level > 0 { 1 } else { 0 };
+                Some(vec![ 0xB0, key, fire_level ])
+            }
+        },
+    }
+}
+
+
 impl ButtonDeviceTrait for MidiDevice {
 
     fn model(&self) -> String {
        self.model.clone()
     }
 
+    fn serial(&self) -> Option<String> {
+        Some(self.in_port_name.clone())
+    }
+
+    fn set_wiring(&mut self, wiring: &[Option<PhysicalKey>]) {
+        self.keymap = MidiKeyMap::from_wiring(wiring);
+    }
+
+    fn specs(&self) -> DeviceSpecs {
+        self.kind.get_specs()
+    }
+
 
     fn start(self, send: mpsc::Sender<DeckEvent>) -> super::Result<mpsc::Sender<DeviceEvent>> {
         
@@ -154,21 +363,26 @@ fn readwrite_thread(mut sd: MidiDevice, rx: Receiver<DeviceEvent>, tx: Sender<De
     
     debug!("readwrite_thread");
 
+    let mut last_check = Instant::now();
+
     loop {
+
+        // midir does not tell us if a device goes away, so
+        // look for the input port once in a while
+        if last_check.elapsed() > PORT_CHECK_INTERVAL {
+            last_check = Instant::now();
+            if !port_exists(&sd.in_port_name) {
+                error!("midi port {} is gone", sd.in_port_name);
+                elog!(tx.send(DeckEvent::Disconnected));
+                return;
+            }
+        }
+
         match sd.receiver.recv_timeout(Duration::from_millis(20)) {
             Ok(DeviceEvent::RawMidi(m)) => {
-                match m {
-                    SendMidi::NoteOn(c, n, v) => {
-                        let nx = n;
-                        tx.send(DeckEvent::Device(DeviceEvent::ButtonDown(n as usize, 1.0)));
-                    },
-                    SendMidi::NoteOff(c, n, v) => {},
-                    SendMidi::PolyphonicKeyPressure(_, _, _) => {},
-                    SendMidi::ControlChange(_, _, _) => {},
-                    SendMidi::ProgramChange(_, _) => {},
-                    SendMidi::ChannelPressure(_, _) => {},
-                    SendMidi::PitchBendChange(_, _) => {},
-                    SendMidi::Other(_) => { },
+                trace!("midi in: {:?}", m);
+                if let Some(ev) = sd.keymap.translate(m) {
+                    elog!(tx.send(DeckEvent::Device(ev)));
                 }
             },
             Ok(e) => {
@@ -178,9 +392,91 @@ fn readwrite_thread(mut sd: MidiDevice, rx: Receiver<DeviceEvent>, tx: Sender<De
                 // trace!("RecvTimeout");
             },
         }
+
+        loop {
+            match rx.try_recv() {
+                Ok(DeviceEvent::SetColor(device_index,color)) => {
+                    debug!("SetColor");
+                    sd.send_color(device_index, &color);
+                },
+                Ok(DeviceEvent::SetImage(_,_)) | Ok(DeviceEvent::PreloadImages(_)) => {
+                    // no displays on midi devices
+                },
+                Ok(DeviceEvent::SetWiring(wiring)) => {
+                    sd.set_wiring(&wiring);
+                },
+                Ok(DeviceEvent::SetFrame(device_index,frame)) => {
+                    // no images or text either, but the background color may light a led
+                    if let Some(color) = &frame.background {
+                        sd.send_color(device_index, color);
+                    }
+                },
+                Ok(DeviceEvent::Shutdown) => {
+                    debug!("Shutdown");
+                    sd.clear();
+                    elog!(tx.send(DeckEvent::Disconnected));
+                    return;
+                },
+                Ok(ev) => {
+                    debug!("Other Event: {:?}", ev);
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    debug!("readwrite_thread: channel closed");
+                    sd.clear();
+                    return;
+                },
+            }
+        }
+    }
+}
+
+/// all midi input ports, with the device kind guessed from the port name
+pub fn list_midi_ports() -> Result<Vec<DiscoveredDevice>> {
+
+    let midi_in = MidiInput::new("MidiList")?;
+
+    let known = [ DeviceKind::AkaiFire, DeviceKind::KorgNanoKontrol2, DeviceKind::TouchOSC ];
+
+    let found = midi_in.ports().iter()
+        .filter_map(|p| midi_in.port_name(p).ok())
+        .map(|name| {
+            let kind = known.iter()
+                .find(|k| k.get_specs().midi_in.map(|n| port_matches(&name, &n)).unwrap_or(false))
+                .cloned()
+                .unwrap_or(DeviceKind::GenericMidi);
+            DiscoveredDevice {
+                kind,
+                model: String::from(kind.model_name()),
+                serial: Some(name),
+                path: None,
+                firmware: None,
+                keys: None,
+                in_use: false,
+            }
+        })
+        .collect();
+
+    Ok(found)
+}
+
+fn port_exists(name: &str) -> bool {
+    match MidiInput::new("MidiCheck") {
+        Ok(mi) => mi.ports().iter()
+            .any(|p| port_matches(&mi.port_name(p).unwrap_or_default(), name)),
+        Err(e) => {
+            error!("cannot create midi input: {:?}", e);
+            false
+        }
     }
 }
 
+// port names carry client/port numbers on some platforms
+// ("FL STUDIO FIRE:FL STUDIO FIRE MIDI 1 20:0"), so a prefix is enough
+fn port_matches(port_name: &str, wanted: &str) -> bool {
+    port_name == wanted || port_name.starts_with(wanted)
+}
+
 // println!("MidiMessage: {:?}", mm);
 // let de = match mm {
 
@@ -208,66 +504,117 @@ pub fn open_midi(device: DeviceKind, ip_name: Option<String>, op_name: Option<St
     
     let in_ports = midi_in.ports();
     let out_ports = midi_out.ports();
-    // let first_in = in_ports.iter()
-    //     .find(|p| midi_in.port_name(&p).unwrap_or(String::new()).starts_with("FL STUDIO FIRE"));
     
     for ip in &in_ports {
-        println!("In-Port: {:?}", midi_in.port_name(&ip))
+        debug!("In-Port: {:?}", midi_in.port_name(&ip))
     }
 
     for op in &out_ports {
-        println!("Out-Port: {:?}", midi_out.port_name(&op))
+        debug!("Out-Port: {:?}", midi_out.port_name(&op))
     }
 
     let specs = device.get_specs();
-    let ipn = ip_name.unwrap_or_else(|| specs.midi_in.unwrap_or_else(|| String::from("TouchOSC")));
-    let opn = op_name.unwrap_or_else(|| specs.midi_out.unwrap_or_else(|| String::from("TouchOSC")));
+
+    let ipn = ip_name.ok_or_else(|| DeckError::Message(String::from("no midi input port configured")))?;
+    let opn = op_name.ok_or_else(|| DeckError::Message(String::from("no midi output port configured")))?;
 
     let in_port = in_ports.into_iter()
-        .find(|p| midi_in.port_name(&p).unwrap_or(String::new()) == ipn );
+        .find(|p| port_matches(&midi_in.port_name(&p).unwrap_or_default(), &ipn))
+        .ok_or(DeckError::NoDevice)?;
 
     let out_port = out_ports.into_iter()
-        .find(|p| midi_out.port_name(&p).unwrap_or(String::new()) == opn );
-
+        .find(|p| port_matches(&midi_out.port_name(&p).unwrap_or_default(), &opn))
+        .ok_or(DeckError::NoDevice)?;
 
 
     let (tx,rx) = mpsc::channel();
-    let tx_move = tx.clone();
         
-    // FIXME unwrap
-    let mut conn_out = midi_out.connect(&out_port.unwrap(), "midir-test")?;
+    let conn_out = midi_out.connect(&out_port, "buttondeck-out")?;
     
-    let mut conn_in  = midi_in.connect(&in_port.unwrap(), "midir-test", move |stamp, message, _| {
+    let conn_in  = midi_in.connect(&in_port, "buttondeck-in", move |stamp, message, _| {
 
         match MidiMessage::try_from(message) {
-            Ok(mm) => { // handle_message(stamp, mm),
-                println!("MidiMessage: {:?}", mm);
+            Ok(mm) => {
+                trace!("MidiMessage: {:?}", mm);
                 if let Err(e) = tx.send(DeviceEvent::RawMidi(SendMidi::from(mm))) {
                     error!("cannot send device event: {:?}", e);
                 }
             }
-            Err(e) => println!("Error: {}", e)
+            Err(e) => error!("Midi Error: {}", e)
         }
 
     }, ())?;
 
-
-
+    info!("connected to midi device {} ({} / {})", device.model_name(), ipn, opn);
     
     Ok(ButtonDevice::Midi(MidiDevice {
         midi_in: conn_in,
         midi_out: conn_out,
         receiver: rx,
-        model: String::from("xxxx"),
+        model: String::from(device.model_name()),
+        kind: device,
+        in_port_name: ipn,
+        keymap: MidiKeyMap::default(),
+        feedback: specs.midi_feedback,
     }))
 
+}
 
 
-    
-    // Err(DeckError::NoDevice)
-//     Ok(ButtonDeviceEnum::NoDevice)
+#[cfg(test)]
+mod tests {
 
-}
+    use super::*;
+    use wmidi::U7;
 
+    const ORANGE: ButtonColor = ButtonColor { rgb: 0xff8000 };
+    const BLACK: ButtonColor = ButtonColor { rgb: 0 };
 
+    #[test]
+    fn note_velocity() {
+        assert_eq!(feedback_message(MidiFeedback::NoteVelocity, 36, false, &ORANGE), Some(vec![ 0x90, 36, 0x7f ]));
+        assert_eq!(feedback_message(MidiFeedback::NoteVelocity, 36, true, &ORANGE), Some(vec![ 0xB0, 36, 0x7f ]));
+        assert_eq!(feedback_message(MidiFeedback::NoteVelocity, 36, false, &BLACK), Some(vec![ 0x90, 36, 0 ]));
+    }
 
+    #[test]
+    fn cc_led() {
+        assert_eq!(feedback_message(MidiFeedback::CcLed, 41, true, &ORANGE), Some(vec![ 0xB0, 41, 0x7f ]));
+        assert_eq!(feedback_message(MidiFeedback::CcLed, 41, true, &BLACK), Some(vec![ 0xB0, 41, 0 ]));
+    }
+
+    #[test]
+    fn akai_fire() {
+        // pads get an rgb sysex, with the pad index from the first pad
+        assert_eq!(feedback_message(MidiFeedback::AkaiFire, FIRE_FIRST_PAD + 3, false, &ORANGE),
+            Some(vec![ 0xF0, 0x47, 0x7F, 0x43, 0x65, 0x00, 0x04, 3, 0x7f, 0x40, 0x00, 0xF7 ]));
+        // the other buttons have led levels
+        assert_eq!(feedback_message(MidiFeedback::AkaiFire, 0x1f, false, &ORANGE), Some(vec![ 0xB0, 0x1f, 2 ]));
+        assert_eq!(feedback_message(MidiFeedback::AkaiFire, 0x1f, false, &ButtonColor { rgb: 0x200000 }), Some(vec![ 0xB0, 0x1f, 1 ]));
+        assert_eq!(feedback_message(MidiFeedback::AkaiFire, 0x1f, false, &BLACK), Some(vec![ 0xB0, 0x1f, 0 ]));
+    }
+
+    #[test]
+    fn notes_and_controllers() {
+
+        let wiring = vec![ Some(PhysicalKey { id: MIDI_CC_BASE + 36, name: String::from("CC36"), kind: KeyKind::CcKey, on_off_threshold: None }) ];
+        let mut km = MidiKeyMap::from_wiring(&wiring);
+
+        let note = SendMidi::NoteOn(Channel::Ch1, Note::from_u8_lossy(36), U7::from_u8_lossy(100));
+        let cc = |n, v| SendMidi::ControlChange(Channel::Ch1, ControlFunction(U7::from_u8_lossy(n)), U7::from_u8_lossy(v));
+
+        // note 36 and controller 36 are different keys
+        assert!(matches!(km.translate(note), Some(DeviceEvent::ButtonDown(36, _))));
+        assert!(matches!(km.translate(cc(36, 127)), Some(DeviceEvent::ButtonDown(k, _)) if k == MIDI_CC_BASE + 36));
+        assert!(matches!(km.translate(cc(36, 0)), Some(DeviceEvent::ButtonUp(k)) if k == MIDI_CC_BASE + 36));
+
+        // unwired controllers send values
+        assert!(matches!(km.translate(cc(7, 64)), Some(DeviceEvent::ButtonValue(k, _)) if k == MIDI_CC_BASE + 7));
+    }
+
+    #[test]
+    fn no_feedback() {
+        assert_eq!(feedback_message(MidiFeedback::None, 36, false, &ORANGE), None);
+    }
+
+}
@@ -1,14 +1,20 @@
 
 mod streamdeck;
 mod midideck;
+mod virtualdeck;
+mod runningdeck;
+mod imagecache;
 
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::mpsc::Sender;
 
 // use crate::ButtonRef;
 use crate::ButtonDeck;
 use crate::DeckEvent;
 use crate::button::ButtonImage;
+use crate::render::KeyFrame;
+use crate::hardware::DeviceSpecs;
 
 use super::{DeckError, Button, ButtonColor};
 
@@ -17,7 +23,13 @@ use self::midideck::SendMidi;
 pub use self::streamdeck::StreamDeckDevice;
 // pub use self::streamdeck::open_streamdeck;
 pub use self::midideck::open_midi;
+pub use self::midideck::list_midi_ports;
 pub use self::streamdeck::discover_streamdeck;
+pub use self::streamdeck::list_streamdecks;
+pub use self::virtualdeck::VirtualDevice;
+pub use self::virtualdeck::VirtualDeviceHandle;
+pub use self::runningdeck::RunningDevice;
+pub use self::imagecache::clear_image_cache;
 
 type Result<T> = std::result::Result<T,DeckError>;
 
@@ -28,7 +40,58 @@ type Result<T> = std::result::Result<T,DeckError>;
 #[derive(Clone, Debug)]
 pub struct PhysicalKey {
     pub id:     usize,
-    pub name:   String
+    pub name:   String,
+    pub kind:   KeyKind,
+    // (off,on) for keys that are driven by a value (e.g. midi cc)
+    pub on_off_threshold: Option<(u8,u8)>,
+}
+
+/// midi controllers get the key ids after the notes (`MIDI_CC_BASE + controller`),
+/// so a note and a controller with the same number are different keys.
+/// The config uses the plain controller number.
+pub const MIDI_CC_BASE: usize = 128;
+
+/// How a physical key reports its events.
+/// For midi devices the key id is the note number (or see [`MIDI_CC_BASE`]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyKind {
+    /// a plain key (stream deck key, midi note)
+    Key,
+    /// a midi controller that is used as a key (with on_off_threshold)
+    CcKey,
+    /// a midi controller that sends values (faders, knobs)
+    CcValue,
+    /// channel pressure (aftertouch)
+    Pressure,
+    /// the pitch bend wheel
+    PitchBend,
+    /// a rotary encoder (stream deck plus)
+    Dial,
+    /// a segment of a touch strip (stream deck plus)
+    Touch,
+}
+
+impl Default for KeyKind {
+    fn default() -> Self {
+        KeyKind::Key
+    }
+}
+
+impl FromStr for KeyKind {
+    type Err = DeckError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "sdkey" | "key" | "note" => Ok(KeyKind::Key),
+            "cckey" => Ok(KeyKind::CcKey),
+            "cc" | "ccvalue" => Ok(KeyKind::CcValue),
+            "pressure" | "aftertouch" => Ok(KeyKind::Pressure),
+            "pitchbend" => Ok(KeyKind::PitchBend),
+            "dial" => Ok(KeyKind::Dial),
+            "touch" => Ok(KeyKind::Touch),
+            _ => Err(DeckError::InvalidKey(format!("unknown key kind '{}'", s)))
+        }
+    }
 }
 
 impl PartialEq for PhysicalKey {
@@ -54,8 +117,33 @@ pub enum DeviceEvent {
     ButtonDown(usize,f32),
     ButtonUp(usize),
 
+    // a new value (0.0 ..= 1.0) from a fader, knob or wheel
+    ButtonValue(usize,f32),
+    // (after)pressure (0.0 ..= 1.0) on a key
+    Pressure(usize,f32),
+
+    // a dial turned by n steps (negative is counter clockwise)
+    Turn(usize,i32),
+    // a tap on a touch segment (true for a long press)
+    Touch(usize,bool),
+    // a swipe from one touch segment to another, the deck passes it on to the
+    // button of the first segment as a value change of (to - from) steps
+    Swipe(usize,usize),
+
     SetImage(usize, ButtonImage),
     SetColor(usize, ButtonColor),
+    // color, image, overlays and text composed into one image,
+    // devices without display show the color
+    SetFrame(usize, KeyFrame),
+
+    // a new wiring for the running device (after a config reload)
+    SetWiring(Vec<Option<PhysicalKey>>),
+    // convert the images of a (reloaded) config before they are needed
+    PreloadImages(Vec<ButtonImage>),
+
+    // clear all keys and stop the device thread,
+    // the device answers with DeckEvent::Disconnected
+    Shutdown,
     // timestamp: u64,
     // pub kind: DeviceEventType,
     // pub index: usize,
@@ -66,9 +154,11 @@ pub enum DeviceEvent {
 
 
 pub enum ButtonDevice {
-//    Dummy(DummyDevice),
     Streamdeck(StreamDeckDevice),
-    Midi(MidiDevice)
+    Midi(MidiDevice),
+    Virtual(VirtualDevice),
+    // already started, see RunningDevice
+    Running(RunningDevice),
 }
 
 impl ButtonDevice {
@@ -79,27 +169,35 @@ impl ButtonDevice {
 
     pub fn as_trait<'a>(&'a self) -> &'a dyn ButtonDeviceTrait {
         let device: &dyn ButtonDeviceTrait = match self {
- //           ButtonDevice::Dummy(d) => d as &dyn ButtonDeviceTrait,
             ButtonDevice::Streamdeck(sd) => sd as &dyn ButtonDeviceTrait,
             ButtonDevice::Midi(md) => md,
+            ButtonDevice::Virtual(vd) => vd,
+            ButtonDevice::Running(rd) => rd,
         };
         device
     }
 
+    /// geometry and capabilities of the device
+    pub fn specs(&self) -> DeviceSpecs {
+        self.as_trait().specs()
+    }
+
     pub fn as_trait_mut<'a>(&'a mut self) -> &'a mut dyn ButtonDeviceTrait {
         let device: &mut dyn ButtonDeviceTrait = match self {
-//            ButtonDevice::Dummy(d) => d as &mut dyn ButtonDeviceTrait,
             ButtonDevice::Streamdeck(sd) => sd as &mut dyn ButtonDeviceTrait,
             ButtonDevice::Midi(md) => md,
+            ButtonDevice::Virtual(vd) => vd,
+            ButtonDevice::Running(rd) => rd,
         };
         device
     }
 
     pub fn start(self, send: Sender<DeckEvent>) -> Result<Sender<DeviceEvent>> {
         match self {
- //           ButtonDevice::Dummy(d) => d.start(send),
             ButtonDevice::Streamdeck(sd) => sd.start(send),
             ButtonDevice::Midi(md) => md.start(send),
+            ButtonDevice::Virtual(vd) => vd.start(send),
+            ButtonDevice::Running(rd) => rd.start(send),
         }
     }
 }
@@ -109,20 +207,14 @@ impl ButtonDevice {
 pub trait ButtonDeviceTrait {
     fn start(self, send: Sender<DeckEvent>) -> Result<Sender<DeviceEvent>>;
     fn model(&self) -> String;
+    /// a unique name of the physical device (serial number, port name)
+    fn serial(&self) -> Option<String> { None }
+    /// called with the wiring of the config before the device is started
+    fn set_wiring(&mut self, _wiring: &[Option<PhysicalKey>]) {}
+    /// called with all images of the config, to convert them before they are needed
+    fn preload_images(&mut self, _images: &[ButtonImage]) {}
+    /// geometry and capabilities of the connected device
+    fn specs(&self) -> DeviceSpecs { DeviceSpecs::default() }
     // fn wait_for_events(&mut self, timeout: usize) -> Result<Vec<DeviceEvent>>;
     // fn decorate_button(&mut self, button: &Button) -> Result<()>;
 }
-
-
-
-// pub struct DummyDevice { }
-
-// impl ButtonDeviceTrait for DummyDevice {
-//     fn start(self, send: Sender<DeckEvent>) -> Result<Sender<DeviceEvent>> {
-//         Err(DeckError::NoDevice)
-//     }
-
-//     fn model(&self) -> String {
-//         String::from("dummy")
-//     }
-// }
\ No newline at end of file
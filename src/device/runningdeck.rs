@@ -0,0 +1,78 @@
+use std::sync::mpsc::Sender;
+
+use log::error;
+
+use crate::DeckEvent;
+use crate::button::ButtonImage;
+use crate::elog;
+use crate::hardware::DeviceSpecs;
+
+use super::{ButtonDeviceTrait, DeviceEvent, PhysicalKey, Result};
+
+
+/// A device that is already started, as seen from the deck thread.
+/// Used to build the setups again (e.g. on a config change) without
+/// reconnecting: the wiring and the images go to the device thread as events,
+/// but only if the build succeeds (see `apply`).
+pub struct RunningDevice {
+    model: String,
+    serial: Option<String>,
+    specs: DeviceSpecs,
+    sender: Sender<DeviceEvent>,
+    wiring: Option<Vec<Option<PhysicalKey>>>,
+    images: Vec<ButtonImage>,
+}
+
+impl RunningDevice {
+
+    pub fn new(model: &str, serial: Option<String>, specs: DeviceSpecs, sender: Sender<DeviceEvent>) -> Self {
+        RunningDevice {
+            model: String::from(model),
+            serial,
+            specs,
+            sender,
+            wiring: None,
+            images: Vec::new(),
+        }
+    }
+
+    /// send the new wiring and images to the device thread
+    pub fn apply(self) {
+        if let Some(w) = self.wiring {
+            elog!("set_wiring", self.sender.send(DeviceEvent::SetWiring(w)));
+        }
+        if !self.images.is_empty() {
+            elog!("preload_images", self.sender.send(DeviceEvent::PreloadImages(self.images)));
+        }
+    }
+
+}
+
+impl ButtonDeviceTrait for RunningDevice {
+
+    fn model(&self) -> String {
+        self.model.clone()
+    }
+
+    fn serial(&self) -> Option<String> {
+        self.serial.clone()
+    }
+
+    fn specs(&self) -> DeviceSpecs {
+        self.specs.clone()
+    }
+
+    fn set_wiring(&mut self, wiring: &[Option<PhysicalKey>]) {
+        self.wiring = Some(wiring.to_vec());
+    }
+
+    fn preload_images(&mut self, images: &[ButtonImage]) {
+        self.images = images.to_vec();
+    }
+
+    // the device thread is running already
+    fn start(self, _send: Sender<DeckEvent>) -> Result<Sender<DeviceEvent>> {
+        Ok(self.sender)
+    }
+
+}
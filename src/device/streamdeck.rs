@@ -1,16 +1,23 @@
 
 
-use std::{time::{Duration, Instant, SystemTime, UNIX_EPOCH}, str::FromStr, sync::mpsc::{Receiver, Sender, TryRecvError}};
+use std::{time::{Duration, Instant, SystemTime, UNIX_EPOCH}, str::FromStr, sync::{Mutex, mpsc::{Receiver, Sender, TryRecvError}}};
+use std::path::Path;
+use std::sync::Arc;
 use std::thread;
 use std::sync::mpsc;
 
 use hidapi::{DeviceInfo, HidApi};
 use log::{error, info, debug, warn};
-use streamdeck::{Colour, ImageOptions};
-use ::streamdeck::{pids, StreamDeck, Kind};
+use streamdeck::{Colour, images::convert_image};
+use ::streamdeck::{pids, StreamDeck, Kind, Input};
+use image::{DynamicImage, Rgb, RgbImage, imageops::FilterType};
 
 
 use crate::{ButtonDeviceTrait, DeviceKind, DeckEvent, elog};
+use crate::hardware::{DeviceSpecs, DiscoveredDevice};
+use crate::render::{KeyFrame, FrameRenderer};
+use crate::button::ButtonImage;
+use super::imagecache::native_image;
 
 use super::{DeckError, Button, ButtonColor, DeviceEvent, ButtonDevice};
 
@@ -19,13 +26,18 @@ type Result<T> = std::result::Result<T,DeckError>;
 
 const ELGATO: u16 = 0x0fd9;
 
-const BUTTON_OFFSETS: [(Kind,usize); 5] = [
-    (Kind::Original, 0),
-    (Kind::OriginalV2, 0),
-    (Kind::Mini, 1),
-    (Kind::Xl, 0),
-    (Kind::Mk2, 0),
-];
+// brightness while running and after shutdown
+const RUNNING_BRIGHTNESS: u8 = 100;
+const DEFAULT_BRIGHTNESS: u8 = 50;
+
+// the stream deck plus has 8 keys, 4 dials and a touch strip with
+// one 200x100 segment above each dial. Dials and strip segments get
+// their own key ids after the keys.
+const PLUS_DIAL_BASE: usize = 8;
+const PLUS_STRIP_BASE: usize = 12;
+const PLUS_DIALS: usize = 4;
+const PLUS_STRIP_SEGMENT: (u32,u32) = (200,100);
+
 
 
 // pub struct MidiDevice {
@@ -39,46 +51,239 @@ const BUTTON_OFFSETS: [(Kind,usize); 5] = [
 
 
 
+// hid paths of the stream decks that are in use by a buttondeck of this process
+static CLAIMED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+
 pub struct StreamDeckDevice {
     
     deck: StreamDeck,
     btn_state: [u8;256],
     index_offset: usize,
     // btn_names: [Option<ButtonName>;256],
-    model: String
+    model: String,
+    serial: Option<String>,
+    path: String,
+    renderer: FrameRenderer,
 
 }
 
 impl StreamDeckDevice {
 
-    fn new(mut sd: StreamDeck) -> Self {
+    fn new(mut sd: StreamDeck, serial: Option<String>, path: String) -> Self {
 
         // let model = sd.product().unwrap_or_else(|e| String::from("unknown")).replace(" ","_").to_lowercase();
 
         let kind = sd.kind();
-        let offs = BUTTON_OFFSETS.iter().find(|(k,o)| k == &kind).map(|o| o.1).unwrap_or(0);
-
-        let model = String::from(match &kind {
-            Kind::Original => "stream_deck",
-            Kind::OriginalV2 => "stream_deck",
-            Kind::Mini => "stream_deck_mini",
-            Kind::RevisedMini => "stream_deck_mini",
-            Kind::Xl => "stream_deck_xl",
-            Kind::Mk2 => "stream_deck",
-            Kind::Plus => "stream_deck_plus",
-        });
+        let offs = specs_for_kind(&kind).first_key;
+
+        let model = String::from(model_name(&kind));
 
         StreamDeckDevice {  
             deck: sd,
             btn_state: [0;256],
             index_offset: offs,
-            model
+            model,
+            serial,
+            path,
+            renderer: FrameRenderer::new(),
         }
     }
 
 
 }
 
+fn is_strip(index: usize) -> bool {
+    index >= PLUS_STRIP_BASE && index < PLUS_STRIP_BASE + PLUS_DIALS
+}
+
+fn send_device_event(tx: &Sender<DeckEvent>, event: DeviceEvent) {
+    elog!(tx.send(DeckEvent::Device(event)));
+}
+
+impl StreamDeckDevice {
+
+    // compare with the last key states and send up/down events
+    fn key_states(&mut self, b: &[u8], tx: &Sender<DeckEvent>) {
+        debug!("Btn: {:?}", b);
+        for i in 0..b.len() {
+            if self.btn_state[i] == 0 && b[i] == 1 {
+                send_device_event(tx, DeviceEvent::ButtonDown(i+self.index_offset, 1.0));
+            } else  if self.btn_state[i] == 1 && b[i] == 0 {
+                send_device_event(tx, DeviceEvent::ButtonUp(i+self.index_offset));
+            }
+            self.btn_state[i] = b[i];
+        }
+    }
+
+    // keys, dials and touch strip of the stream deck plus
+    fn read_plus(&mut self, tx: &Sender<DeckEvent>) -> std::result::Result<(), streamdeck::Error> {
+
+        match self.deck.read_input(Some(Duration::from_millis(20)))? {
+            Input::Buttons(b) => {
+                self.key_states(&b, tx);
+            },
+            Input::EncoderPress(p) => {
+                for i in 0..p.len().min(PLUS_DIALS) {
+                    let id = PLUS_DIAL_BASE + i;
+                    if self.btn_state[id] == 0 && p[i] == 1 {
+                        send_device_event(tx, DeviceEvent::ButtonDown(id, 1.0));
+                    } else if self.btn_state[id] == 1 && p[i] == 0 {
+                        send_device_event(tx, DeviceEvent::ButtonUp(id));
+                    }
+                    self.btn_state[id] = p[i];
+                }
+            },
+            Input::EncoderTwist(t) => {
+                for (i,delta) in t.iter().enumerate().take(PLUS_DIALS) {
+                    if *delta != 0 {
+                        send_device_event(tx, DeviceEvent::Turn(PLUS_DIAL_BASE + i, *delta as i32));
+                    }
+                }
+            },
+            Input::TouchPress(x, _y) => {
+                send_device_event(tx, DeviceEvent::Touch(strip_segment(x), false));
+            },
+            Input::TouchLongPress(x, _y) => {
+                send_device_event(tx, DeviceEvent::Touch(strip_segment(x), true));
+            },
+            Input::TouchSwipe((x0, _y0), (x1, _y1)) => {
+                send_device_event(tx, DeviceEvent::Swipe(strip_segment(x0), strip_segment(x1)));
+            },
+        }
+
+        Ok(())
+    }
+
+    // draw an image on one segment of the touch strip
+    fn write_strip(&mut self, index: usize, img: &DynamicImage) {
+        let (w,h) = PLUS_STRIP_SEGMENT;
+        let x = ((index - PLUS_STRIP_BASE) as u32 * w) as u16;
+        let scaled = img.resize_to_fill(w, h, FilterType::Triangle);
+        elog!("write strip", self.deck.set_lcd_image(x, 0, &scaled));
+    }
+
+    // image files in the native format of the device, from the image cache
+    fn native_image(&self, path: &Path) -> Result<Arc<Vec<u8>>> {
+        let kind = self.deck.kind();
+        let (w,h) = kind.image_size();
+        native_image(path, image_format(&kind), (w as u32, h as u32), |img| Ok(convert_image(kind, img)?))
+    }
+
+    fn write_image(&mut self, index: usize, image: &ButtonImage) {
+        let written = match image.path() {
+            Some(p) => match self.native_image(p) {
+                Ok(data) => self.deck.write_button_image(index as u8, &data),
+                Err(e) => {
+                    error!("cannot load {:?}: {:?}", p, e);
+                    return;
+                }
+            },
+            None => match image.load() {
+                Ok(img) => self.deck.set_button_image(index as u8, img),
+                Err(e) => {
+                    error!("cannot load {:?}: {:?}", image.source, e);
+                    return;
+                }
+            }
+        };
+        elog!("write image", written);
+    }
+
+    fn write_frame(&mut self, index: usize, frame: &KeyFrame) {
+
+        let strip = self.deck.kind() == Kind::Plus && is_strip(index);
+        let (w,h) = if strip {
+            PLUS_STRIP_SEGMENT
+        } else {
+            let (w,h) = self.deck.kind().image_size();
+            (w as u32, h as u32)
+        };
+
+        match self.renderer.render(index, frame, w, h) {
+            Ok(img) if strip => self.write_strip(index, &img),
+            Ok(img) => elog!("write frame", self.deck.set_button_image(index as u8, img)),
+            Err(e) => error!("cannot render frame for key {}: {:?}", index, e)
+        }
+    }
+
+    // all keys black, brightness back to normal
+    fn clear(&mut self) {
+        let black = Colour { r: 0, g: 0, b: 0 };
+        for k in 0..key_count(&self.deck.kind()) {
+            elog!(self.deck.set_button_rgb((k + self.index_offset) as u8, &black));
+        }
+        elog!(self.deck.set_brightness(DEFAULT_BRIGHTNESS));
+    }
+
+}
+
+impl Drop for StreamDeckDevice {
+    fn drop(&mut self) {
+        release(&self.path);
+    }
+}
+
+// returns false if the device is already used by another buttondeck
+fn claim(path: &str) -> bool {
+    match CLAIMED.lock() {
+        Ok(mut claimed) => {
+            if claimed.iter().any(|p| p == path) {
+                false
+            } else {
+                claimed.push(String::from(path));
+                true
+            }
+        },
+        Err(e) => {
+            error!("claim: {:?}", e);
+            false
+        }
+    }
+}
+
+fn release(path: &str) {
+    if let Ok(mut claimed) = CLAIMED.lock() {
+        claimed.retain(|p| p != path);
+    }
+}
+
+fn is_claimed(path: &str) -> bool {
+    CLAIMED.lock().map(|c| c.iter().any(|p| p == path)).unwrap_or(false)
+}
+
+// key id of the strip segment at x
+fn strip_segment(x: u16) -> usize {
+    let segment = (x as u32 / PLUS_STRIP_SEGMENT.0) as usize;
+    PLUS_STRIP_BASE + segment.min(PLUS_DIALS - 1)
+}
+
+fn model_name(kind: &Kind) -> &'static str {
+    match kind {
+        Kind::Original => "stream_deck",
+        Kind::OriginalV2 => "stream_deck",
+        Kind::Mini => "stream_deck_mini",
+        Kind::RevisedMini => "stream_deck_mini",
+        Kind::Xl => "stream_deck_xl",
+        Kind::Mk2 => "stream_deck",
+        Kind::Plus => "stream_deck_plus",
+    }
+}
+
+// the image cache key of a kind. Kinds with the same model name still
+// differ in the image format (bmp or jpeg) and rotation
+fn image_format(kind: &Kind) -> &'static str {
+    match kind {
+        Kind::Original => "original",
+        Kind::OriginalV2 => "original_v2",
+        Kind::Mini => "mini",
+        Kind::RevisedMini => "revised_mini",
+        Kind::Xl => "xl",
+        Kind::Mk2 => "mk2",
+        Kind::Plus => "plus",
+    }
+}
+
 fn to_colour(c: &ButtonColor) -> Colour {
     Colour {
         r: ((c.rgb&0xff0000) >> 16) as u8,
@@ -93,6 +298,22 @@ impl ButtonDeviceTrait for StreamDeckDevice {
         self.model.clone()
     }
 
+    fn serial(&self) -> Option<String> {
+        self.serial.clone()
+    }
+
+    fn specs(&self) -> DeviceSpecs {
+        specs_for_kind(&self.deck.kind())
+    }
+
+    fn preload_images(&mut self, images: &[ButtonImage]) {
+        for p in images.iter().filter_map(|i| i.path()) {
+            if let Err(e) = self.native_image(p) {
+                warn!("cannot preload {:?}: {:?}", p, e);
+            }
+        }
+    }
+
 
     fn start(self, send_to_buttondeck: Sender<DeckEvent>) -> Result<Sender<DeviceEvent>> {
 
@@ -116,24 +337,21 @@ fn readwrite_thread(mut sd: StreamDeckDevice, rx: Receiver<DeviceEvent>, tx: Sen
 
     debug!("readwrite_thread");
 
-    sd.deck.set_brightness(100);
+    sd.deck.set_brightness(RUNNING_BRIGHTNESS);
+
+    let is_plus = sd.deck.kind() == Kind::Plus;
 
     loop {
 
-        let btns = sd.deck.read_buttons(Some(Duration::from_millis(20)));
+        let read = if is_plus {
+            sd.read_plus(&tx)
+        } else {
+            sd.deck.read_buttons(Some(Duration::from_millis(20)))
+                .map(|b| sd.key_states(&b, &tx))
+        };
 
-        match btns {
-            Ok(b) => {
-                debug!("Btn: {:?}", b);
-                for i in 0..b.len() {
-                    if sd.btn_state[i] == 0 && b[i] == 1 {
-                        elog!(tx.send(DeckEvent::Device(DeviceEvent::ButtonDown(i+sd.index_offset, 1.0)))); 
-                    } else  if sd.btn_state[i] == 1 && b[i] == 0 {
-                        elog!(tx.send(DeckEvent::Device(DeviceEvent::ButtonUp(i+sd.index_offset))))
-                    }
-                    sd.btn_state[i] = b[i];
-                }
-            },
+        match read {
+            Ok(()) => {},
             Err(streamdeck::Error::NoData) => {
                 // nothing to do
             },
@@ -147,19 +365,54 @@ fn readwrite_thread(mut sd: StreamDeckDevice, rx: Receiver<DeviceEvent>, tx: Sen
 
         loop {
             match rx.try_recv() {
+                Ok(DeviceEvent::SetImage(device_index,image)) if is_plus && is_strip(device_index) => {
+                    debug!("SetImage (strip)");
+                    match image.load() {
+                        Ok(img) => sd.write_strip(device_index, &img),
+                        Err(e) => error!("cannot load {:?}: {:?}", image.source, e)
+                    }
+                },
+                Ok(DeviceEvent::SetColor(device_index,color)) if is_plus && is_strip(device_index) => {
+                    debug!("SetColor (strip)");
+                    let (w,h) = PLUS_STRIP_SEGMENT;
+                    let c = to_colour(&color);
+                    let img = RgbImage::from_pixel(w, h, Rgb([c.r, c.g, c.b]));
+                    sd.write_strip(device_index, &DynamicImage::ImageRgb8(img));
+                },
+                Ok(DeviceEvent::SetFrame(device_index,frame)) => {
+                    debug!("SetFrame");
+                    sd.write_frame(device_index, &frame);
+                },
                 Ok(DeviceEvent::SetImage(device_index,image)) => {
                     debug!("SetImage");
-                    sd.deck.set_button_file((device_index) as u8, &image.path.to_string_lossy(),&ImageOptions::default());
+                    sd.write_image(device_index, &image);
                 },
                 Ok(DeviceEvent::SetColor(device_index,color)) => {
                     debug!("SetColor");
-                    sd.deck.set_button_rgb((device_index) as u8, &to_colour(&color));
+                    elog!("set color", sd.deck.set_button_rgb((device_index) as u8, &to_colour(&color)));
+                },
+                Ok(DeviceEvent::PreloadImages(images)) => {
+                    sd.preload_images(&images);
+                },
+                Ok(DeviceEvent::SetWiring(_)) => {
+                    // the keys of a stream deck are fixed
+                },
+                Ok(DeviceEvent::Shutdown) => {
+                    debug!("Shutdown");
+                    sd.clear();
+                    elog!(tx.send(DeckEvent::Disconnected));
+                    return;
                 },
                 Ok(ev) => {
                     error!("Other event {:?}",ev);
                 }
                 Err(TryRecvError::Empty) => break,
-                Err(TryRecvError::Disconnected) => break,
+                Err(TryRecvError::Disconnected) => {
+                    // the buttondeck is gone (or uses another device now)
+                    debug!("readwrite_thread: channel closed");
+                    sd.clear();
+                    return;
+                },
             }
         }
     }
@@ -171,7 +424,133 @@ fn readwrite_thread(mut sd: StreamDeckDevice, rx: Receiver<DeviceEvent>, tx: Sen
 
 
 
-pub fn discover_streamdeck(maybe_hidapi: &mut Option<HidApi>) -> Result<ButtonDevice> {
+// all product ids we know how to handle
+fn all_pids() -> Vec<u16> {
+    vec![
+        pids::ORIGINAL,
+        pids::ORIGINAL_V2,
+        pids::MINI,
+        pids::REVISED_MINI,
+        pids::XL,
+        pids::REVISED_XL,
+        pids::MK2,
+        pids::PLUS,
+    ]
+}
+
+fn kind_for_pid(pid: u16) -> Option<Kind> {
+    match pid {
+        pids::ORIGINAL => Some(Kind::Original),
+        pids::ORIGINAL_V2 => Some(Kind::OriginalV2),
+        pids::MINI => Some(Kind::Mini),
+        pids::REVISED_MINI => Some(Kind::RevisedMini),
+        pids::XL => Some(Kind::Xl),
+        pids::REVISED_XL => Some(Kind::Xl),
+        pids::MK2 => Some(Kind::Mk2),
+        pids::PLUS => Some(Kind::Plus),
+        _ => None
+    }
+}
+
+fn device_kind(kind: &Kind) -> DeviceKind {
+    match kind {
+        Kind::Original => DeviceKind::StreamDeckOriginal,
+        Kind::OriginalV2 => DeviceKind::StreamDeckOriginalV2,
+        Kind::Mini => DeviceKind::StreamDeckMini,
+        Kind::RevisedMini => DeviceKind::StreamDeckMini,
+        Kind::Xl => DeviceKind::StreamDeckXL,
+        Kind::Mk2 => DeviceKind::StreamDeckMK2,
+        Kind::Plus => DeviceKind::StreamDeckPlus,
+    }
+}
+
+fn key_count(kind: &Kind) -> usize {
+    kind.keys() as usize
+}
+
+// the specs of the model, with the geometry the streamdeck crate reports
+fn specs_for_kind(kind: &Kind) -> DeviceSpecs {
+
+    let (w,h) = kind.image_size();
+    let cols = kind.key_columns() as usize;
+
+    let specs = device_kind(kind).get_specs();
+
+    DeviceSpecs {
+        cols,
+        rows: key_count(kind).checked_div(cols).unwrap_or(0),
+        key_size: Some((w as u32, h as u32)),
+        // the revised mini counts its keys from 0, the old one from 1
+        first_key: if matches!(kind, Kind::RevisedMini) { 0 } else { specs.first_key },
+        ..specs
+    }
+}
+
+
+/// all attached stream decks. The firmware is only read from devices 
+/// that are not in use by a buttondeck of this process.
+pub fn list_streamdecks(hidapi: &mut HidApi) -> Result<Vec<DiscoveredDevice>> {
+
+    hidapi.refresh_devices()?;
+
+    let alldecks = all_pids();
+
+    let infos: Vec<&DeviceInfo> = hidapi.device_list()
+        .filter(|d| d.vendor_id() == ELGATO && alldecks.contains(&d.product_id()))
+        .collect();
+
+    let mut found = Vec::new();
+
+    for info in infos {
+
+        let kind = match kind_for_pid(info.product_id()) {
+            Some(k) => k,
+            None => continue
+        };
+
+        let path = info.path().to_string_lossy().into_owned();
+        let serial = info.serial_number().map(|s| String::from(s));
+        let in_use = is_claimed(&path);
+
+        let firmware = if in_use || ambiguous(hidapi, info) {
+            None
+        } else {
+            match StreamDeck::connect_with_hid(&hidapi, info.vendor_id(), info.product_id(), serial.clone()) {
+                Ok(mut sd) => sd.version().ok(),
+                Err(e) => {
+                    warn!("cannot read firmware of {}: {:?}", path, e);
+                    None
+                }
+            }
+        };
+
+        found.push(DiscoveredDevice {
+            kind: device_kind(&kind),
+            model: String::from(model_name(&kind)),
+            serial,
+            path: Some(path),
+            firmware,
+            keys: Some(key_count(&kind)),
+            in_use,
+        });
+    }
+
+    Ok(found)
+}
+
+
+// the stream deck is opened by vid/pid and serial. without a serial hidapi
+// takes the first deck with this vid/pid, which need not be the one in `info`
+fn ambiguous(hidapi: &HidApi, info: &DeviceInfo) -> bool {
+    info.serial_number().is_none() && hidapi.device_list()
+        .filter(|d| d.vendor_id() == info.vendor_id() && d.product_id() == info.product_id())
+        .count() > 1
+}
+
+
+/// connect to the first stream deck that is not used by another buttondeck
+/// of this process. If `serial` or `path` is given, only this device will be used.
+pub fn discover_streamdeck(maybe_hidapi: &mut Option<HidApi>, serial: Option<&str>, path: Option<&str>) -> Result<ButtonDevice> {
 
     info!("Discover Streamdeck");
     let hidapi = maybe_hidapi.as_mut().ok_or(DeckError::NoHidApi)?;
@@ -181,18 +560,13 @@ pub fn discover_streamdeck(maybe_hidapi: &mut Option<HidApi>) -> Result<ButtonDe
         error!("{:?}",e);
     }
 
-    let alldecks: Vec<u16> = vec![
-        pids::ORIGINAL,
-        pids::ORIGINAL_V2,
-        pids::MINI,
-        pids::REVISED_MINI,
-        pids::XL,
-        pids::REVISED_XL,
-        pids::MK2,
-    ];
+    let alldecks = all_pids();
 
     let devinfo: Vec<&DeviceInfo> = hidapi.device_list().into_iter()
         .filter(|d| d.vendor_id() == ELGATO && alldecks.contains(&d.product_id()))
+        .filter(|d| serial.is_none() || d.serial_number() == serial)
+        .filter(|d| path.is_none() || Some(d.path().to_string_lossy().as_ref()) == path)
+        .filter(|d| !is_claimed(&d.path().to_string_lossy()))
         .collect(); 
 
     for i in &devinfo {
@@ -206,14 +580,26 @@ pub fn discover_streamdeck(maybe_hidapi: &mut Option<HidApi>) -> Result<ButtonDe
     }
 
     let deviceinfo = devinfo[0];
+    let serial = deviceinfo.serial_number().map(|s| String::from(s));
 
+    if ambiguous(hidapi, deviceinfo) {
+        return Err(DeckError::Message(format!("cannot open the stream deck at {}, it has no serial number and another deck of the same model is attached",
+            deviceinfo.path().to_string_lossy())))
+    }
+
+    let path = deviceinfo.path().to_string_lossy().into_owned();
+
+    if !claim(&path) {
+        return Err(DeckError::NoDevice)
+    }
 
-    match StreamDeck::connect_with_hid(&hidapi, deviceinfo.vendor_id(), deviceinfo.product_id(), deviceinfo.serial_number().map(|s| String::from(s))) {
+    match StreamDeck::connect_with_hid(&hidapi, deviceinfo.vendor_id(), deviceinfo.product_id(), serial.clone()) {
         Ok(sd) => {
-            Ok(ButtonDevice::Streamdeck(StreamDeckDevice::new(sd)))
+            Ok(ButtonDevice::Streamdeck(StreamDeckDevice::new(sd, serial, path)))
         },
         Err(e) => {
             error!("Error connecting to streamdeck: {:?}", e);
+            release(&path);
             Err(DeckError::NoDevice)
         }
     }
@@ -0,0 +1,225 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error};
+
+use crate::{ButtonDeviceTrait, DeckError, DeckEvent, DeviceSpecs, elog};
+
+use super::DeviceEvent;
+
+type Result<T> = std::result::Result<T,DeckError>;
+
+
+// shared slot for the sender to the buttondeck, filled when the
+// device is started (and replaced on every reconnect)
+type DeckSlot = Arc<Mutex<Option<Sender<DeckEvent>>>>;
+
+
+/// An in-memory device without any hardware behind it.
+///
+/// Events the buttondeck sends to the device (SetImage, SetColor, Shutdown, ...)
+/// can be read back from the [`VirtualDeviceHandle`], button presses
+/// can be injected through it.
+#[derive(Clone)]
+pub struct VirtualDevice {
+    model: String,
+    serial: Option<String>,
+    keys: usize,
+    specs: DeviceSpecs,
+    to_handle: Sender<DeviceEvent>,
+    deck: DeckSlot,
+}
+
+/// The "other side" of a [`VirtualDevice`], used by tests or simulations
+pub struct VirtualDeviceHandle {
+    keys: usize,
+    from_deck: Receiver<DeviceEvent>,
+    deck: DeckSlot,
+}
+
+
+impl VirtualDevice {
+
+    /// create a virtual device with `keys` buttons. `model` is the name that is used
+    /// to look up the device template (e.g. "stream_deck_mini")
+    pub fn new(model: &str, keys: usize) -> (VirtualDevice, VirtualDeviceHandle) {
+
+        let (tx,rx) = mpsc::channel();
+        let deck: DeckSlot = Arc::new(Mutex::new(None));
+
+        let device = VirtualDevice {
+            model: String::from(model),
+            serial: None,
+            keys,
+            specs: DeviceSpecs {
+                rows: 1,
+                cols: keys,
+                ..Default::default()
+            },
+            to_handle: tx,
+            deck: deck.clone(),
+        };
+
+        let handle = VirtualDeviceHandle {
+            keys,
+            from_deck: rx,
+            deck,
+        };
+
+        (device, handle)
+    }
+
+    /// give the device a serial number (to select a template from `devices`)
+    pub fn with_serial(mut self, serial: &str) -> Self {
+        self.serial = Some(String::from(serial));
+        self
+    }
+
+    /// pretend to be a device with this geometry (e.g. `DeviceKind::StreamDeckXL.get_specs()`)
+    pub fn with_specs(mut self, specs: DeviceSpecs) -> Self {
+        self.specs = specs;
+        self
+    }
+
+    pub fn keys(&self) -> usize {
+        self.keys
+    }
+
+}
+
+
+impl ButtonDeviceTrait for VirtualDevice {
+
+    fn model(&self) -> String {
+        self.model.clone()
+    }
+
+    fn serial(&self) -> Option<String> {
+        self.serial.clone()
+    }
+
+    fn specs(&self) -> DeviceSpecs {
+        self.specs.clone()
+    }
+
+    fn start(self, send: Sender<DeckEvent>) -> Result<Sender<DeviceEvent>> {
+
+        debug!("VirtualDevice start");
+
+        match self.deck.lock() {
+            Ok(mut slot) => *slot = Some(send.clone()),
+            Err(_) => return Err(DeckError::Message(String::from("virtual device lock poisoned")))
+        }
+
+        let (tx,rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            forward_thread(self, rx, send);
+        });
+
+        Ok(tx)
+    }
+
+}
+
+
+// everything sent to the device goes to the handle,
+// like a real device we confirm a shutdown 
+fn forward_thread(vd: VirtualDevice, rx: Receiver<DeviceEvent>, tx: Sender<DeckEvent>) {
+
+    for event in rx.iter() {
+
+        let shutdown = matches!(event, DeviceEvent::Shutdown);
+
+        if vd.to_handle.send(event).is_err() {
+            debug!("virtual device handle is gone");
+        }
+
+        if shutdown {
+            elog!(tx.send(DeckEvent::Disconnected));
+            return;
+        }
+    }
+
+    debug!("forward_thread: channel closed");
+}
+
+
+impl VirtualDeviceHandle {
+
+    pub fn keys(&self) -> usize {
+        self.keys
+    }
+
+    /// true if the device has been started by a buttondeck
+    pub fn is_connected(&self) -> bool {
+        self.deck.lock().map(|s| s.is_some()).unwrap_or(false)
+    }
+
+    /// inject a raw device event into the buttondeck
+    pub fn inject(&self, event: DeviceEvent) -> Result<()> {
+        self.send_to_deck(DeckEvent::Device(event))
+    }
+
+    pub fn button_down(&self, index: usize, velocity: f32) -> Result<()> {
+        self.inject(DeviceEvent::ButtonDown(index, velocity))
+    }
+
+    pub fn button_up(&self, index: usize) -> Result<()> {
+        self.inject(DeviceEvent::ButtonUp(index))
+    }
+
+    /// button_down followed by button_up
+    pub fn press(&self, index: usize) -> Result<()> {
+        self.button_down(index, 1.0)?;
+        self.button_up(index)
+    }
+
+    /// simulate unplugging the device
+    pub fn disconnect(&self) -> Result<()> {
+        self.send_to_deck(DeckEvent::Disconnected)?;
+        if let Ok(mut slot) = self.deck.lock() {
+            *slot = None;
+        }
+        Ok(())
+    }
+
+    /// next event sent by the buttondeck, if any
+    pub fn try_recv(&self) -> Option<DeviceEvent> {
+        match self.from_deck.try_recv() {
+            Ok(e) => Some(e),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => None,
+        }
+    }
+
+    /// wait up to `timeout` for the next event sent by the buttondeck
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<DeviceEvent> {
+        match self.from_deck.recv_timeout(timeout) {
+            Ok(e) => Some(e),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    /// all events sent by the buttondeck so far
+    pub fn drain(&self) -> Vec<DeviceEvent> {
+        let mut events = Vec::new();
+        while let Some(e) = self.try_recv() {
+            events.push(e);
+        }
+        events
+    }
+
+    fn send_to_deck(&self, event: DeckEvent) -> Result<()> {
+        let slot = self.deck.lock()
+            .map_err(|_| DeckError::Message(String::from("virtual device lock poisoned")))?;
+        match slot.as_ref() {
+            Some(tx) => tx.send(event).map_err(|_| DeckError::Disconnected),
+            None => Err(DeckError::NoDevice)
+        }
+    }
+
+}
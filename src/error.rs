@@ -1,5 +1,7 @@
 
 
+use std::fmt;
+
 use midir::{MidiInput, MidiOutput};
 use thiserror::Error;
 
@@ -15,6 +17,8 @@ pub enum DeckError {
     InvalidRef,
     #[error("button key error {0}")]
     InvalidKey(String),
+    #[error("invalid config: {} problem(s), first: {}", .0.len(), .0.first().map(|p| p.to_string()).unwrap_or_default())]
+    InvalidConfig(Vec<ConfigProblem>),
     #[error("no directory")]
     NoDirectory,
     #[error("disconnected")]
@@ -27,8 +31,16 @@ pub enum DeckError {
     HidError(#[from] hidapi::HidError),
     #[error("serde error: `{0}`")]
     SerdeError(#[from] serde_json::Error),
+    #[error("toml error: `{0}`")]
+    TomlError(#[from] toml::de::Error),
+    #[error("yaml error: `{0}`")]
+    YamlError(#[from] serde_yaml::Error),
+    #[error("json5 error: `{0}`")]
+    Json5Error(#[from] json5::Error),
     #[error("streamdeck error: `{0}`")]
     StreamdeckError(#[from] streamdeck::Error),
+    #[error("image error: `{0}`")]
+    ImageError(#[from] image::ImageError),
     #[error("no hid api")]
     NoHidApi,
     #[error("MidiSendError")]
@@ -40,3 +52,18 @@ pub enum DeckError {
     #[error("MpscSendError")]
     SendDeviceEventError(#[from] std::sync::mpsc::SendError<DeviceEvent>),
 }
+
+
+/// Something wrong in the config, `path` is where it is in the json
+/// (e.g. `controls.mute.states.on.image`)
+#[derive(Clone,Debug)]
+pub struct ConfigProblem {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
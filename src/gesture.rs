@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::ButtonId;
+
+
+/// Thresholds for gesture detection
+#[derive(Clone, Copy, Debug)]
+pub struct GestureTiming {
+    /// hold at least this long for a long press
+    pub long_press: Duration,
+    /// max time between two taps of a double tap
+    pub double_tap: Duration,
+    /// hold this long before auto repeat starts
+    pub repeat_delay: Duration,
+    /// time between two repeats
+    pub repeat_interval: Duration,
+}
+
+impl Default for GestureTiming {
+    fn default() -> Self {
+        GestureTiming {
+            long_press: Duration::from_millis(600),
+            double_tap: Duration::from_millis(300),
+            repeat_delay: Duration::from_millis(500),
+            repeat_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+
+/// which gestures a button listens to (from its current state)
+#[derive(Clone, Copy, Debug, Default)]
+pub (crate) struct GestureBindings {
+    pub long_press: bool,
+    pub double_tap: bool,
+    pub repeat: bool,
+}
+
+impl GestureBindings {
+    // with long press or double tap, the button down actions
+    // have to wait until we know it was a simple tap
+    pub fn deferred(&self) -> bool {
+        self.long_press || self.double_tap
+    }
+}
+
+
+#[derive(Debug)]
+pub (crate) enum Gesture {
+    Tap(ButtonId),
+    DoubleTap(ButtonId),
+    LongPress(ButtonId),
+    Repeat(ButtonId),
+}
+
+
+struct KeyGesture {
+    button: ButtonId,
+    bindings: GestureBindings,
+    down_at: Option<Instant>,
+    long_fired: bool,
+    // second press of a double tap, ignore the release
+    consumed: bool,
+    next_repeat: Option<Instant>,
+    // release of the first tap, waiting for a second one
+    tap_pending: Option<Instant>,
+}
+
+
+/// Tracks presses per key and turns them into gestures
+pub (crate) struct GestureTracker {
+    timing: GestureTiming,
+    keys: HashMap<usize, KeyGesture>,
+}
+
+impl GestureTracker {
+
+    pub fn new(timing: GestureTiming) -> Self {
+        GestureTracker {
+            timing,
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+    }
+
+    pub fn press(&mut self, index: usize, button: ButtonId, bindings: GestureBindings, now: Instant) -> Option<Gesture> {
+
+        if let Some(kg) = self.keys.get_mut(&index) {
+            if kg.button == button {
+                if let Some(t) = kg.tap_pending {
+                    if now.duration_since(t) < self.timing.double_tap {
+                        kg.tap_pending = None;
+                        kg.consumed = true;
+                        kg.down_at = Some(now);
+                        return Some(Gesture::DoubleTap(button));
+                    }
+                }
+            }
+        }
+
+        let next_repeat = if bindings.repeat {
+            Some(now + self.timing.repeat_delay)
+        } else {
+            None
+        };
+
+        self.keys.insert(index, KeyGesture {
+            button,
+            bindings,
+            down_at: Some(now),
+            long_fired: false,
+            consumed: false,
+            next_repeat,
+            tap_pending: None,
+        });
+
+        None
+    }
+
+    pub fn release(&mut self, index: usize, now: Instant) -> Option<Gesture> {
+
+        let kg = self.keys.get_mut(&index)?;
+
+        kg.down_at = None;
+        kg.next_repeat = None;
+
+        if kg.consumed {
+            kg.consumed = false;
+            return None;
+        }
+
+        if !kg.bindings.deferred() || kg.long_fired {
+            return None;
+        }
+
+        if kg.bindings.double_tap {
+            kg.tap_pending = Some(now);
+            None
+        } else {
+            Some(Gesture::Tap(kg.button))
+        }
+    }
+
+    /// gestures that are due at `now`
+    pub fn tick(&mut self, now: Instant) -> Vec<Gesture> {
+
+        let mut due = Vec::new();
+
+        for kg in self.keys.values_mut() {
+
+            if let Some(down) = kg.down_at {
+                if kg.bindings.long_press && !kg.long_fired && !kg.consumed
+                    && now.duration_since(down) >= self.timing.long_press {
+                    kg.long_fired = true;
+                    due.push(Gesture::LongPress(kg.button));
+                }
+            }
+
+            if let Some(t) = kg.next_repeat {
+                if now >= t {
+                    kg.next_repeat = Some(t + self.timing.repeat_interval);
+                    due.push(Gesture::Repeat(kg.button));
+                }
+            }
+
+            if let Some(t) = kg.tap_pending {
+                if now.duration_since(t) >= self.timing.double_tap {
+                    kg.tap_pending = None;
+                    due.push(Gesture::Tap(kg.button));
+                }
+            }
+        }
+
+        due
+    }
+
+    /// the next time `tick` has something to do
+    pub fn next_deadline(&self) -> Option<Instant> {
+
+        let mut next: Option<Instant> = None;
+        let mut consider = |t: Instant| {
+            next = Some(match next {
+                Some(n) if n < t => n,
+                _ => t
+            });
+        };
+
+        for kg in self.keys.values() {
+            if let Some(down) = kg.down_at {
+                if kg.bindings.long_press && !kg.long_fired && !kg.consumed {
+                    consider(down + self.timing.long_press);
+                }
+            }
+            if let Some(t) = kg.next_repeat {
+                consider(t);
+            }
+            if let Some(t) = kg.tap_pending {
+                consider(t + self.timing.double_tap);
+            }
+        }
+
+        next
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::DeckId;
+
+    const BUTTON: ButtonId = ButtonId { deck: DeckId { index: 0 }, index: 0 };
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn tracker() -> GestureTracker {
+        GestureTracker::new(GestureTiming::default())
+    }
+
+    fn bindings(long_press: bool, double_tap: bool, repeat: bool) -> GestureBindings {
+        GestureBindings { long_press, double_tap, repeat }
+    }
+
+    #[test]
+    fn plain_press() {
+        // nothing deferred, the deck runs the down and up actions itself
+        let mut gt = tracker();
+        let t = Instant::now();
+        assert!(gt.press(0, BUTTON, bindings(false, false, false), t).is_none());
+        assert!(gt.release(0, t + ms(50)).is_none());
+        assert!(gt.next_deadline().is_none());
+    }
+
+    #[test]
+    fn tap() {
+        let mut gt = tracker();
+        let t = Instant::now();
+        assert!(gt.press(0, BUTTON, bindings(true, false, false), t).is_none());
+        assert!(matches!(gt.release(0, t + ms(100)), Some(Gesture::Tap(_))));
+        assert!(gt.tick(t + ms(1000)).is_empty());
+    }
+
+    #[test]
+    fn tap_waits_for_double_tap() {
+        let mut gt = tracker();
+        let t = Instant::now();
+        gt.press(0, BUTTON, bindings(false, true, false), t);
+        assert!(gt.release(0, t + ms(50)).is_none());
+        assert_eq!(gt.next_deadline(), Some(t + ms(350)));
+        assert!(gt.tick(t + ms(349)).is_empty());
+        assert!(matches!(gt.tick(t + ms(350)).as_slice(), [Gesture::Tap(_)]));
+    }
+
+    #[test]
+    fn double_tap() {
+        let mut gt = tracker();
+        let t = Instant::now();
+        gt.press(0, BUTTON, bindings(false, true, false), t);
+        gt.release(0, t + ms(50));
+        assert!(matches!(gt.press(0, BUTTON, bindings(false, true, false), t + ms(200)), Some(Gesture::DoubleTap(_))));
+        assert!(gt.release(0, t + ms(250)).is_none());
+        assert!(gt.tick(t + ms(1000)).is_empty());
+    }
+
+    #[test]
+    fn long_press() {
+        let mut gt = tracker();
+        let t = Instant::now();
+        gt.press(0, BUTTON, bindings(true, false, false), t);
+        assert_eq!(gt.next_deadline(), Some(t + ms(600)));
+        assert!(gt.tick(t + ms(599)).is_empty());
+        assert!(matches!(gt.tick(t + ms(600)).as_slice(), [Gesture::LongPress(_)]));
+        assert!(gt.tick(t + ms(700)).is_empty());
+        // no tap after a long press
+        assert!(gt.release(0, t + ms(800)).is_none());
+    }
+
+    #[test]
+    fn repeat() {
+        let mut gt = tracker();
+        let t = Instant::now();
+        gt.press(0, BUTTON, bindings(false, false, true), t);
+        assert!(gt.tick(t + ms(499)).is_empty());
+        assert!(matches!(gt.tick(t + ms(500)).as_slice(), [Gesture::Repeat(_)]));
+        assert!(gt.tick(t + ms(550)).is_empty());
+        assert!(matches!(gt.tick(t + ms(600)).as_slice(), [Gesture::Repeat(_)]));
+        gt.release(0, t + ms(650));
+        assert!(gt.tick(t + ms(1000)).is_empty());
+        assert!(gt.next_deadline().is_none());
+    }
+
+}
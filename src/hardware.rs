@@ -1,5 +1,13 @@
+use hidapi::HidApi;
+use log::warn;
 
-#[derive(Debug)]
+use crate::DeckError;
+use crate::device::{list_streamdecks, list_midi_ports, MIDI_CC_BASE};
+
+type Result<T> = std::result::Result<T,DeckError>;
+
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
 pub enum DeviceFamily {
     Midi,
     Streamdeck
@@ -11,7 +19,7 @@ impl Default for DeviceFamily {
     }
 }
 
-#[derive(Clone,Copy)]
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
 pub enum DeviceKind {
     GenericMidi,
     AkaiFire,
@@ -23,6 +31,7 @@ pub enum DeviceKind {
     StreamDeckMini,
     StreamDeckXL,
     StreamDeckMK2,
+    StreamDeckPlus,
 }
 
 
@@ -34,46 +43,148 @@ impl Default for DeviceKind {
 }
 
 impl DeviceKind {
+
+    /// the model name of midi devices, used to find the device template in the config.
+    /// (stream decks report their model themselves)
+    pub fn model_name(&self) -> &'static str {
+        match self {
+            DeviceKind::GenericMidi => "generic_midi",
+            DeviceKind::AkaiFire => "akai_fire",
+            DeviceKind::TouchOSC => "touchosc",
+            DeviceKind::KorgNanoKontrol2 => "nano_kontrol2",
+            DeviceKind::StreamDeck => "stream_deck",
+            DeviceKind::StreamDeckOriginal => "stream_deck",
+            DeviceKind::StreamDeckOriginalV2 => "stream_deck",
+            DeviceKind::StreamDeckMini => "stream_deck_mini",
+            DeviceKind::StreamDeckXL => "stream_deck_xl",
+            DeviceKind::StreamDeckMK2 => "stream_deck",
+            DeviceKind::StreamDeckPlus => "stream_deck_plus",
+        }
+    }
+
+    /// the key grid of stream decks, None for midi devices
+    /// and for `StreamDeck` (any model)
+    pub fn key_layout(&self) -> Option<KeyLayout> {
+        self.get_specs().key_layout()
+    }
+
     pub fn get_specs(&self) -> DeviceSpecs {
         match self {
             DeviceKind::GenericMidi => DeviceSpecs { 
                 family: DeviceFamily::Midi,
+                midi_feedback: MidiFeedback::NoteVelocity,
+                key_leds: LedCapability::Levels(128),
                 ..Default::default()
             },
             DeviceKind::StreamDeck  => DeviceSpecs { 
                 family: DeviceFamily::Streamdeck,
+                key_leds: LedCapability::Display,
                 ..Default::default()
             },
             DeviceKind::AkaiFire    => DeviceSpecs { 
                 family: DeviceFamily::Midi,
+                midi_in: Some(String::from("FL STUDIO FIRE")),
+                midi_out: Some(String::from("FL STUDIO FIRE")),
+                midi_feedback: MidiFeedback::AkaiFire,
+                // 4 x 16 rgb pads, the other buttons have 2 or 3 led levels
+                rows: 4,
+                cols: 16,
+                first_key: 0x36,
+                encoders: 5,
+                key_leds: LedCapability::Levels(3),
+                led_ranges: vec![ (0x36, 0x75, LedCapability::Rgb) ],
                 ..Default::default()
             },
             DeviceKind::TouchOSC => DeviceSpecs { 
                 family: DeviceFamily::Midi,
+                midi_in: Some(String::from("TouchOSC")),
+                midi_out: Some(String::from("TouchOSC")),
+                midi_feedback: MidiFeedback::NoteVelocity,
+                touchscreen: true,
+                key_leds: LedCapability::Levels(128),
                 ..Default::default()
             },
             DeviceKind::KorgNanoKontrol2 => DeviceSpecs { 
                 family: DeviceFamily::Midi,
+                midi_in: Some(String::from("nanoKONTROL2 SLIDER/KNOB")),
+                midi_out: Some(String::from("nanoKONTROL2 CTRL")),
+                midi_feedback: MidiFeedback::CcLed,
+                // solo, mute and record buttons of 8 channels, controllers 32.., 48.. and 64..
+                rows: 3,
+                cols: 8,
+                key_ids: [32, 48, 64].iter().flat_map(|r| (*r..*r + 8).map(|cc| MIDI_CC_BASE + cc)).collect(),
+                encoders: 8,
+                key_leds: LedCapability::OnOff,
                 ..Default::default()
             },
             DeviceKind::StreamDeckMini => DeviceSpecs { 
                 family: DeviceFamily::Streamdeck,
+                rows: 2,
+                cols: 3,
+                first_key: 1,
+                key_size: Some((80,80)),
+                key_gap: 16,
+                image_format: ImageFormat::Bmp,
+                image_rotation: 90,
+                image_flip: (false, true),
+                key_leds: LedCapability::Display,
                 ..Default::default()
             },
             DeviceKind::StreamDeckOriginal => DeviceSpecs { 
                 family: DeviceFamily::Streamdeck,
+                rows: 3,
+                cols: 5,
+                key_size: Some((72,72)),
+                key_gap: 16,
+                image_format: ImageFormat::Bmp,
+                image_flip: (true, true),
+                key_leds: LedCapability::Display,
                 ..Default::default()
             },
             DeviceKind::StreamDeckOriginalV2 => DeviceSpecs { 
                 family: DeviceFamily::Streamdeck,
+                rows: 3,
+                cols: 5,
+                key_size: Some((72,72)),
+                key_gap: 16,
+                image_format: ImageFormat::Jpeg,
+                image_flip: (true, true),
+                key_leds: LedCapability::Display,
                 ..Default::default()
             },
             DeviceKind::StreamDeckXL => DeviceSpecs { 
                 family: DeviceFamily::Streamdeck,
+                rows: 4,
+                cols: 8,
+                key_size: Some((96,96)),
+                key_gap: 18,
+                image_format: ImageFormat::Jpeg,
+                image_flip: (true, true),
+                key_leds: LedCapability::Display,
                 ..Default::default()
             },
             DeviceKind::StreamDeckMK2 => DeviceSpecs { 
                 family: DeviceFamily::Streamdeck,
+                rows: 3,
+                cols: 5,
+                key_size: Some((72,72)),
+                key_gap: 16,
+                image_format: ImageFormat::Jpeg,
+                image_flip: (true, true),
+                key_leds: LedCapability::Display,
+                ..Default::default()
+            },
+            DeviceKind::StreamDeckPlus => DeviceSpecs { 
+                family: DeviceFamily::Streamdeck,
+                rows: 2,
+                cols: 4,
+                key_size: Some((120,120)),
+                key_gap: 30,
+                image_format: ImageFormat::Jpeg,
+                encoders: 4,
+                touchscreen: true,
+                touch_size: Some((800,100)),
+                key_leds: LedCapability::Display,
                 ..Default::default()
             },
         }
@@ -81,11 +192,164 @@ impl DeviceKind {
 }
 
 
-#[derive(Debug,Default)]
+/// How button colors are sent back to a midi device
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum MidiFeedback {
+    /// no feedback
+    None,
+    /// NoteOn on the key's note, velocity is the brightness of the color
+    NoteVelocity,
+    /// ControlChange on the key's controller, 127 for on and 0 for off
+    CcLed,
+    /// RGB SysEx for the pads and CC led levels for the buttons
+    AkaiFire,
+}
+
+impl Default for MidiFeedback {
+    fn default() -> Self {
+        MidiFeedback::None
+    }
+}
+
+/// How keys show images
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ImageFormat {
+    /// no display on the keys
+    None,
+    Bmp,
+    Jpeg,
+}
+
+impl Default for ImageFormat {
+    fn default() -> Self {
+        ImageFormat::None
+    }
+}
+
+/// What the light of a key can show
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum LedCapability {
+    None,
+    OnOff,
+    /// a number of brightness levels
+    Levels(u8),
+    Rgb,
+    /// a display (stream deck keys)
+    Display,
+}
+
+impl Default for LedCapability {
+    fn default() -> Self {
+        LedCapability::None
+    }
+}
+
+#[derive(Debug,Clone,Default)]
 pub struct DeviceSpecs {
     pub family: DeviceFamily,
     pub midi_in: Option<String>,
     pub midi_out: Option<String>,
+    pub midi_feedback: MidiFeedback,
+
+    /// the key grid, 0 if there is none
+    pub rows: usize,
+    pub cols: usize,
+    /// key id of the top left key
+    pub first_key: usize,
+    /// the key ids by row, if the keys are not numbered from `first_key`
+    pub key_ids: Vec<usize>,
+
+    /// pixels of a key image, None without displays
+    pub key_size: Option<(u32,u32)>,
+    /// space between two keys, in pixels of the key image
+    pub key_gap: u32,
+    pub image_format: ImageFormat,
+    /// clockwise, in degrees
+    pub image_rotation: u16,
+    /// mirror (horizontal, vertical)
+    pub image_flip: (bool,bool),
+
+    /// number of dials or knobs
+    pub encoders: usize,
+    pub touchscreen: bool,
+    /// pixels of the touch strip
+    pub touch_size: Option<(u32,u32)>,
+
+    /// the lights of all keys, unless `led_ranges` says otherwise
+    pub key_leds: LedCapability,
+    /// (first key, last key, capability)
+    pub led_ranges: Vec<(usize,usize,LedCapability)>,
+}
+
+impl DeviceSpecs {
+
+    pub fn key_count(&self) -> usize {
+        self.rows * self.cols
+    }
+
+    /// the id of the n-th key of the grid
+    pub fn key_id(&self, n: usize) -> usize {
+        self.key_ids.get(n).copied().unwrap_or(self.first_key + n)
+    }
+
+    /// what the light of a key can do
+    pub fn led(&self, key: usize) -> LedCapability {
+        self.led_ranges.iter()
+            .find(|(first,last,_)| key >= *first && key <= *last)
+            .map(|r| r.2)
+            .unwrap_or(self.key_leds)
+    }
+
+    /// the grid of display keys
+    pub fn key_layout(&self) -> Option<KeyLayout> {
+        match self.key_size {
+            Some(key_size) if self.rows > 0 && self.cols > 0 => Some(KeyLayout {
+                cols: self.cols,
+                rows: self.rows,
+                key_size,
+                gap: self.key_gap,
+                first_key: self.first_key,
+            }),
+            _ => None
+        }
+    }
+
+}
+
+
+/// The grid of display keys, used to spread one image over all keys
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct KeyLayout {
+    pub cols: usize,
+    pub rows: usize,
+    /// pixels of one key image
+    pub key_size: (u32,u32),
+    /// space between two keys, in pixels of the key image
+    pub gap: u32,
+    /// key id of the top left key
+    pub first_key: usize,
+}
+
+impl KeyLayout {
+
+    /// pixels of the whole panel, including the gaps
+    pub fn panel_size(&self) -> (u32,u32) {
+        let (w,h) = self.key_size;
+        let (c,r) = (self.cols as u32, self.rows as u32);
+        (c * w + (c - 1) * self.gap, r * h + (r - 1) * self.gap)
+    }
+
+    /// key id, x and y of each key on the panel, row by row
+    pub fn keys(&self) -> Vec<(usize,u32,u32)> {
+        let (w,h) = self.key_size;
+        (0..self.rows * self.cols)
+            .map(|n| {
+                let (c,r) = ((n % self.cols) as u32, (n / self.cols) as u32);
+                (self.first_key + n, c * (w + self.gap), r * (h + self.gap))
+            })
+            .collect()
+    }
+
 }
 
 
@@ -97,6 +361,51 @@ pub enum ButtonClass {
 }
 
 
-pub fn discover() {
+/// A device found by `discover`
+#[derive(Debug,Clone)]
+pub struct DiscoveredDevice {
+    pub kind: DeviceKind,
+    /// the model name, as used for the device templates in the config
+    pub model: String,
+    /// serial number of stream decks, port name of midi devices
+    pub serial: Option<String>,
+    /// hid path of stream decks
+    pub path: Option<String>,
+    pub firmware: Option<String>,
+    pub keys: Option<usize>,
+    /// already used by a buttondeck of this process
+    pub in_use: bool,
+}
+
+/// all attached stream decks and midi ports
+pub fn discover(hidapi: &mut HidApi) -> Result<Vec<DiscoveredDevice>> {
+
+    let mut found = list_streamdecks(hidapi)?;
+
+    match list_midi_ports() {
+        Ok(midi) => found.extend(midi),
+        Err(e) => warn!("cannot list midi ports: {:?}", e)
+    }
 
-}
\ No newline at end of file
+    Ok(found)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn key_ids() {
+        let fire = DeviceKind::AkaiFire.get_specs();
+        assert_eq!((fire.key_id(0), fire.key_id(63)), (0x36, 0x75));
+
+        // solo, mute and record rows of the nanoKONTROL2 are controllers
+        let nano = DeviceKind::KorgNanoKontrol2.get_specs();
+        assert_eq!(nano.key_count(), 24);
+        assert_eq!(nano.key_id(0), MIDI_CC_BASE + 32);
+        assert_eq!(nano.key_id(15), MIDI_CC_BASE + 55);
+        assert_eq!(nano.key_id(23), MIDI_CC_BASE + 71);
+    }
+}
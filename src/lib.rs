@@ -5,15 +5,33 @@ mod device;
 mod setup;
 mod hardware;
 mod sx;
+mod decks;
+mod gesture;
+mod animation;
+mod render;
+mod watch;
 
 pub use error::DeckError;
+pub use error::ConfigProblem;
 pub use device::ButtonDeviceTrait;
+pub use device::DeviceEvent;
+pub use device::VirtualDevice;
+pub use device::VirtualDeviceHandle;
+pub use device::clear_image_cache;
 
 pub use hardware::DeviceKind;
 pub use hardware::DeviceFamily;
 pub use hardware::DeviceSpecs;
+pub use hardware::MidiFeedback;
+pub use hardware::DiscoveredDevice;
+pub use hardware::KeyLayout;
+pub use hardware::ImageFormat;
+pub use hardware::LedCapability;
+pub use hardware::discover;
 
 pub use setup::ButtonDeckBuilder;
+pub use setup::config_schema;
+pub use setup::device_config_schema;
 
 pub use deck::ButtonDeck;
 pub use deck::ButtonFn;
@@ -22,11 +40,23 @@ pub use deck::DeckEvent;
 pub use deck::ButtonSetup;
 pub use deck::ButtonDeckSender;
 
+pub use decks::ButtonDecks;
+pub use decks::DeckHandle;
+
+pub use gesture::GestureTiming;
+pub use animation::Effect;
+
 pub use button::Button;
 pub use button::ButtonColor;
 pub use button::ButtonState;
 pub use button::ButtonImage;
+pub use button::ImageData;
 pub use button::ButtonValue;
+pub use button::ValueChange;
+
+pub use render::KeyFrame;
+pub use render::KeyLabel;
+pub use render::TextAlign;
 
 
 #[macro_export]
@@ -44,7 +74,7 @@ macro_rules! elog {
 }
 
 
-#[derive(Clone,Copy,Debug)]
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
 pub struct DeckId {
     index: usize
 }
@@ -56,6 +86,16 @@ pub struct SetupId {
  //    pub name: String
 }
 
+/// Where a button switches to
+#[derive(Clone,Copy,Debug)]
+pub enum SetupTarget {
+    Setup(SetupId),
+    /// the setup we came from (`"back"` in the config)
+    Back,
+    /// the first setup, forgets the way back (`"home"` in the config)
+    Home,
+}
+
 // impl Default for SetupId {
 //     fn default() -> Self {
 //         Self { index: 0, name: String::from("default") }
@@ -64,7 +104,7 @@ pub struct SetupId {
 
 
 
-#[derive(Clone,Copy,Debug)]
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
 pub struct ButtonId {
     deck: DeckId,
     index: usize
@@ -85,6 +125,10 @@ impl ButtonId {
         ButtonId { deck: owner, index }
     }
 
+    pub fn deck(&self) -> DeckId {
+        self.deck
+    }
+
     // pub fn id(&self) -> usize {
     //     self.index
     // }
@@ -0,0 +1,378 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use ab_glyph::{point, Font, FontArc, PxScale, ScaleFont};
+use image::{DynamicImage, Rgba, RgbaImage, imageops::{self, FilterType}};
+use log::{debug, warn};
+
+use crate::{ButtonColor, ButtonImage, DeckError};
+use crate::hardware::KeyLayout;
+
+type Result<T> = std::result::Result<T,DeckError>;
+
+// used if the config does not name a font
+const DEFAULT_FONTS: &[&str] = &[
+    "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+    "/usr/share/fonts/TTF/DejaVuSans.ttf",
+    "/usr/share/fonts/dejavu/DejaVuSans.ttf",
+    "/Library/Fonts/Arial.ttf",
+    "/System/Library/Fonts/Supplemental/Arial.ttf",
+    "C:\\Windows\\Fonts\\arial.ttf",
+];
+
+// used if none of the default fonts is installed
+const BUNDLED_FONT: &[u8] = include_bytes!("../fonts/DejaVuSans.ttf");
+
+const DEFAULT_FONT_SIZE: f32 = 18.0;
+
+// space between text and key border
+const TEXT_PADDING: f32 = 4.0;
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TextAlign {
+    Top,
+    #[default]
+    Center,
+    Bottom,
+}
+
+impl FromStr for TextAlign {
+    type Err = DeckError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "top" => Ok(TextAlign::Top),
+            "center" | "middle" => Ok(TextAlign::Center),
+            "bottom" => Ok(TextAlign::Bottom),
+            _ => Err(DeckError::Message(format!("unknown text alignment '{}'", s)))
+        }
+    }
+}
+
+
+/// Text to draw on a key
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyLabel {
+    pub text: String,
+    pub font: Option<PathBuf>,
+    pub size: f32,
+    pub align: TextAlign,
+    pub color: ButtonColor,
+}
+
+impl KeyLabel {
+    pub fn new(text: &str) -> Self {
+        KeyLabel {
+            text: String::from(text),
+            font: None,
+            size: DEFAULT_FONT_SIZE,
+            align: TextAlign::default(),
+            color: ButtonColor { rgb: 0xffffff },
+        }
+    }
+}
+
+
+/// Everything that ends up on one key, from bottom to top:
+/// background color, base image, overlays and text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyFrame {
+    pub background: Option<ButtonColor>,
+    pub image: Option<ButtonImage>,
+    pub overlays: Vec<ButtonImage>,
+    pub label: Option<KeyLabel>,
+    /// 0.0 (black) ..= 1.0 (unchanged)
+    pub brightness: f32,
+}
+
+impl KeyFrame {
+
+    /// true if the frame is a plain image or a plain color
+    /// and can be sent to the device without compositing
+    pub fn is_simple(&self) -> bool {
+        self.overlays.is_empty() && self.label.is_none() && self.brightness >= 1.0
+            && !(self.background.is_some() && self.image.is_some())
+    }
+
+    /// true if there is nothing but a color
+    pub fn is_color(&self) -> bool {
+        self.image.is_none() && self.overlays.is_empty() && self.label.is_none()
+    }
+
+}
+
+impl Default for KeyFrame {
+    fn default() -> Self {
+        KeyFrame {
+            background: None,
+            image: None,
+            overlays: Vec::new(),
+            label: None,
+            brightness: 1.0,
+        }
+    }
+}
+
+
+/// Renders [`KeyFrame`]s into key images, fonts are loaded once and
+/// image files are decoded once per size (and again if they changed)
+#[derive(Default)]
+pub struct FrameRenderer {
+    fonts: HashMap<PathBuf, FontArc>,
+    // fonts that could not be loaded, to warn only once
+    bad_fonts: HashSet<PathBuf>,
+    // None until looked up, Some(None) if there is no usable font at all
+    default_font: Option<Option<FontArc>>,
+    layers: HashMap<(PathBuf,(u32,u32)), CachedLayer>,
+    // the last undimmed image of each key, for pulses
+    composed: HashMap<usize,(KeyFrame,RgbaImage)>,
+}
+
+// an image file, scaled to the key
+struct CachedLayer {
+    modified: Option<SystemTime>,
+    image: Arc<RgbaImage>,
+}
+
+impl FrameRenderer {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// render the frame for a key. A frame that differs from the last one of the
+    /// key only in brightness (a pulse) is not composed again, just dimmed
+    pub fn render(&mut self, key: usize, frame: &KeyFrame, width: u32, height: u32) -> Result<DynamicImage> {
+
+        let base = KeyFrame { brightness: 1.0, ..frame.clone() };
+
+        let cached = match self.composed.get(&key) {
+            Some((f, img)) if frame.brightness < 1.0 && *f == base && img.dimensions() == (width, height) => Some(img.clone()),
+            _ => None
+        };
+
+        let mut canvas = match cached {
+            Some(img) => img,
+            None => {
+                let img = self.compose(&base, width, height)?;
+                self.composed.insert(key, (base, img.clone()));
+                img
+            }
+        };
+
+        dim(&mut canvas, frame.brightness);
+        Ok(DynamicImage::ImageRgba8(canvas))
+    }
+
+    // background, images and text, without the brightness
+    fn compose(&mut self, frame: &KeyFrame, width: u32, height: u32) -> Result<RgbaImage> {
+
+        let (r,g,b) = match &frame.background {
+            Some(c) => c.to_rgb8(),
+            None => (0,0,0)
+        };
+        let mut canvas = RgbaImage::from_pixel(width, height, Rgba([r, g, b, 255]));
+
+        for layer in frame.image.iter().chain(frame.overlays.iter()) {
+            match self.layer(layer, width, height) {
+                Ok(i) => imageops::overlay(&mut canvas, i.as_ref(), 0, 0),
+                Err(e) => warn!("cannot load {:?}: {:?}", layer.source, e)
+            }
+        }
+
+        if let Some(label) = &frame.label {
+            if !label.text.is_empty() {
+                if let Some(font) = self.font(label.font.as_deref()) {
+                    draw_text(&mut canvas, &font, label);
+                }
+            }
+        }
+
+        Ok(canvas)
+    }
+
+    // the image scaled to the key, files are only read if they are new or changed
+    fn layer(&mut self, layer: &ButtonImage, width: u32, height: u32) -> Result<Arc<RgbaImage>> {
+
+        let path = match layer.path() {
+            Some(p) => p,
+            None => return Ok(Arc::new(scale_layer(layer.load()?, width, height)))
+        };
+
+        let key = (path.to_path_buf(), (width, height));
+        let mtime = modified(path);
+
+        if let Some(c) = self.layers.get(&key) {
+            if c.modified == mtime {
+                return Ok(c.image.clone());
+            }
+            debug!("layer changed: {:?}", path);
+        }
+
+        let image = Arc::new(scale_layer(layer.load()?, width, height));
+        self.layers.insert(key, CachedLayer { modified: mtime, image: image.clone() });
+        Ok(image)
+    }
+
+    // the font of the label or the default font. Without any font
+    // the text is not drawn
+    fn font(&mut self, path: Option<&Path>) -> Option<FontArc> {
+
+        if let Some(p) = path {
+            if let Some(f) = self.fonts.get(p) {
+                return Some(f.clone());
+            }
+            if !self.bad_fonts.contains(p) {
+                match load_font(p) {
+                    Ok(f) => {
+                        self.fonts.insert(p.to_path_buf(), f.clone());
+                        return Some(f);
+                    },
+                    Err(e) => {
+                        warn!("cannot load font {:?}, using the default font: {:?}", p, e);
+                        self.bad_fonts.insert(p.to_path_buf());
+                    }
+                }
+            }
+        }
+
+        self.default_font.get_or_insert_with(|| {
+            let found = DEFAULT_FONTS.iter()
+                .map(Path::new)
+                .filter(|p| p.exists())
+                .find_map(|p| load_font(p).ok())
+                .or_else(|| FontArc::try_from_slice(BUNDLED_FONT).ok());
+            if found.is_none() {
+                warn!("no font found, keys are drawn without text");
+            }
+            found
+        }).clone()
+    }
+
+}
+
+
+/// cut a panel image into one image per key (key id, image)
+pub fn slice_panel(panel: &DynamicImage, layout: &KeyLayout) -> Vec<(usize,ButtonImage)> {
+
+    let (pw,ph) = layout.panel_size();
+    let (w,h) = layout.key_size;
+    let scaled = panel.resize_to_fill(pw, ph, FilterType::Triangle);
+
+    layout.keys().into_iter()
+        .map(|(key,x,y)| (key, ButtonImage::from_image(&scaled.crop_imm(x, y, w, h))))
+        .collect()
+}
+
+
+fn load_font(path: &Path) -> Result<FontArc> {
+    debug!("load font {:?}", path);
+    let data = std::fs::read(path)?;
+    FontArc::try_from_vec(data)
+        .map_err(|e| DeckError::Message(format!("invalid font {:?}: {}", path, e)))
+}
+
+
+fn dim(canvas: &mut RgbaImage, brightness: f32) {
+    if brightness < 1.0 {
+        let f = brightness.max(0.0);
+        for p in canvas.pixels_mut() {
+            for c in 0..3 {
+                p[c] = (p[c] as f32 * f) as u8;
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// scale an image to fill the key
+fn scale_layer(image: DynamicImage, width: u32, height: u32) -> RgbaImage {
+    image.resize_to_fill(width, height, FilterType::Triangle).to_rgba8()
+}
+
+
+fn line_width<F: Font>(font: &F, scale: PxScale, line: &str) -> f32 {
+    let scaled = font.as_scaled(scale);
+    let mut width = 0.0;
+    let mut last = None;
+    for c in line.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(prev) = last {
+            width += scaled.kern(prev, id);
+        }
+        width += scaled.h_advance(id);
+        last = Some(id);
+    }
+    width
+}
+
+
+fn draw_text(canvas: &mut RgbaImage, font: &FontArc, label: &KeyLabel) {
+
+    let (width, height) = (canvas.width() as f32, canvas.height() as f32);
+    let lines: Vec<&str> = label.text.lines().collect();
+
+    // shrink the font until the longest line fits
+    let mut scale = PxScale::from(label.size);
+    let widest = lines.iter()
+        .map(|l| line_width(font, scale, l))
+        .fold(0.0, f32::max);
+    let room = width - 2.0 * TEXT_PADDING;
+    if widest > room && widest > 0.0 {
+        scale = PxScale::from(label.size * room / widest);
+    }
+
+    let scaled = font.as_scaled(scale);
+    let line_height = scaled.height() + scaled.line_gap();
+    let total = line_height * lines.len() as f32;
+
+    let top = match label.align {
+        TextAlign::Top => TEXT_PADDING,
+        TextAlign::Center => (height - total) / 2.0,
+        TextAlign::Bottom => height - TEXT_PADDING - total,
+    };
+
+    let (r,g,b) = label.color.to_rgb8();
+
+    for (n, line) in lines.iter().enumerate() {
+
+        let baseline = top + n as f32 * line_height + scaled.ascent();
+        let mut x = (width - line_width(font, scale, line)) / 2.0;
+        let mut last = None;
+
+        for c in line.chars() {
+
+            let id = scaled.glyph_id(c);
+            if let Some(prev) = last {
+                x += scaled.kern(prev, id);
+            }
+            last = Some(id);
+
+            let glyph = id.with_scale_and_position(scale, point(x, baseline));
+            x += scaled.h_advance(id);
+
+            if let Some(outline) = font.outline_glyph(glyph) {
+                let bounds = outline.px_bounds();
+                outline.draw(|gx, gy, coverage| {
+                    let px = bounds.min.x as i32 + gx as i32;
+                    let py = bounds.min.y as i32 + gy as i32;
+                    if px < 0 || py < 0 || px >= canvas.width() as i32 || py >= canvas.height() as i32 {
+                        return;
+                    }
+                    let p = canvas.get_pixel_mut(px as u32, py as u32);
+                    let blend = |under: u8, over: u8| {
+                        (under as f32 * (1.0 - coverage) + over as f32 * coverage) as u8
+                    };
+                    *p = Rgba([blend(p[0], r), blend(p[1], g), blend(p[2], b), 255]);
+                });
+            }
+        }
+    }
+}
@@ -1,24 +1,40 @@
-use std::{fs::File, sync::{Arc, atomic::{AtomicUsize, Ordering}}, collections::HashMap, rc::Rc, cell::RefCell, path::{PathBuf, Path}, thread::{self, JoinHandle}, time::Instant};
+use std::{fs::File, str::FromStr, sync::{Arc, atomic::{AtomicUsize, Ordering}}, collections::HashMap, rc::Rc, cell::RefCell, path::{PathBuf, Path}, thread::{self, JoinHandle}, time::Instant};
 
 use hidapi::HidApi;
 use indexmap::IndexMap;
 use serde_derive::{Serialize,Deserialize};
+use serde::de::DeserializeOwned;
 use serde_json::Value;
+use schemars::JsonSchema;
 
-use crate::{Button, ButtonSetup, ButtonState, ButtonColor, deck::{ButtonMapping, FnRef, FnArg, DeckDeviceSetup}, device::{PhysicalKey, ButtonDevice, DeviceEvent}, DeviceFamily, DeviceKind, ButtonDeviceTrait, DeckEvent, button::{ButtonImage, ButtonValue}, ButtonId, DeckId, StateId};
-use crate::SetupId;
-use super::{DeckError, ButtonDeck, device::StreamDeckDevice, ButtonFn};
+use crate::{Button, ButtonSetup, ButtonState, ButtonColor, deck::{ButtonMapping, FnRef, FnArg, DeckDeviceSetup, SetupGrid}, device::{PhysicalKey, KeyKind, ButtonDevice, DeviceEvent, VirtualDevice, MIDI_CC_BASE}, DeviceFamily, DeviceKind, ButtonDeviceTrait, DeckEvent, button::{ButtonImage, ButtonText, ButtonValue}, render::{TextAlign, slice_panel}, hardware::{DeviceSpecs, KeyLayout}, ButtonId, DeckId, StateId};
+use crate::{SetupId, SetupTarget};
+use crate::animation::{Animator, Effect};
+use crate::gesture::{GestureTiming, GestureTracker};
+use super::{DeckError, ConfigProblem, ButtonDeck, device::StreamDeckDevice, ButtonFn};
 
 use log::{error, debug, warn, info, trace};
 
+mod validate;
+mod include;
+use validate::{ConfigPaths, Validator};
+
 type Result<T> = std::result::Result<T,DeckError>;
 
 
 static idgen: AtomicUsize = AtomicUsize::new(1);
 
-#[derive(Default,Serialize,Deserialize)]
+// reserved setup names for switch_deck_setup and long_press_setup
+const SETUP_BACK: &str = "back";
+const SETUP_HOME: &str = "home";
+
+#[derive(Default,Serialize,Deserialize,JsonSchema)]
+#[schemars(deny_unknown_fields)]
 struct DeckJson {
 
+    // files with controls, setups and grids (see setup/include.rs)
+    include:  Option<Vec<String>>,
+
     assets:   Option<String>,
 
     midi_in:  Option<String>,
@@ -28,82 +44,155 @@ struct DeckJson {
 
     controls: Option<IndexMap<String,ButtonTemplate>>,
     setups:   Option<IndexMap<String,SetupTemplate>>,
+    grids:    Option<IndexMap<String,SetupTemplate>>,
 
 
     deck: Option<ButtonDeckTemplate>,
 
 }
 
-#[derive(Serialize,Deserialize)]
+#[derive(Serialize,Deserialize,JsonSchema)]
+#[schemars(deny_unknown_fields)]
 struct ButtonDeckTemplate {
     label:    Option<String>,
+    // files with wiring, controls, setups and grids
+    include:  Option<Vec<String>>,
+    // generated from the device geometry if missing
+    #[serde(default)]
     wiring:   IndexMap<String,PhysicalKeyTemplate>,
     controls: Option<IndexMap<String,ButtonTemplate>>,
     setups:   Option<IndexMap<String,SetupTemplate>>,
+    grids:    Option<IndexMap<String,SetupTemplate>>,
 }
 
 
-#[derive(Serialize,Deserialize)]
+#[derive(Serialize,Deserialize,JsonSchema)]
+#[schemars(deny_unknown_fields)]
 pub struct PhysicalKeyTemplate {
     id:   usize,
+    kind: Option<String>,
+    on_off_threshold: Option<(u8,u8)>,
 }
 
 impl PhysicalKeyTemplate {
     pub fn into_key(&self, name: &str) -> Result<PhysicalKey> {
+
+        let kind = match &self.kind {
+            Some(k) => KeyKind::from_str(k)?,
+            None => KeyKind::default()
+        };
+
+        // controllers have their own ids, see MIDI_CC_BASE
+        let id = match kind {
+            KeyKind::CcKey | KeyKind::CcValue if self.id < 128 => MIDI_CC_BASE + self.id,
+            KeyKind::CcKey | KeyKind::CcValue => {
+                return Err(DeckError::InvalidKey(format!("controller {} is out of midi range (0..127)", self.id)));
+            },
+            _ => self.id
+        };
+
         Ok(PhysicalKey {
-            id: self.id,
+            id,
             name: String::from(name),
+            kind,
+            on_off_threshold: self.on_off_threshold,
         })
     }
 }
 
 
 
-#[derive(Serialize,Deserialize)]
+#[derive(Serialize,Deserialize,Default,JsonSchema)]
+#[schemars(deny_unknown_fields)]
 struct ButtonTemplate {
 
     label: Option<String>,
     color: Option<String>,
     image: Option<String>,
+    overlays: Option<Vec<String>>,
+    effect: Option<String>,
 
     #[serde(default)]
     value: Value,
 
+    text: Option<String>,
+    font: Option<String>,
+    font_size: Option<f32>,
+    text_align: Option<String>,
+    text_color: Option<String>,
+
     on_up: Option<String>,
     on_down:  Option<String>,
     on_value: Option<String>,
 
+    on_long_press: Option<String>,
+    on_double_tap: Option<String>,
+    long_press_setup: Option<String>,
+    repeat: Option<bool>,
+
     switch_button_state: Option<String>,
     switch_deck_setup: Option<String>,
+    // flip the pages of a grid setup by n
+    page: Option<i32>,
 
     states: Option<IndexMap<String,StateTemplate>>
 }
 
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Serialize, Deserialize, Default,JsonSchema)]
+#[schemars(deny_unknown_fields)]
 struct StateTemplate {
 
     color: Option<String>,
     image: Option<String>,
+    overlays: Option<Vec<String>>,
     effect: Option<String>,
 
     #[serde(default)]
     value: Value,
 
+    text: Option<String>,
+    font: Option<String>,
+    font_size: Option<f32>,
+    text_align: Option<String>,
+    text_color: Option<String>,
+
     on_up: Option<String>,
     on_down: Option<String>,
     on_value: Option<String>,
 
+    on_long_press: Option<String>,
+    on_double_tap: Option<String>,
+    long_press_setup: Option<String>,
+    repeat: Option<bool>,
+
     switch_button_state: Option<String>,
     switch_deck_setup: Option<String>,
+    page: Option<i32>,
 }
 
-#[derive(Serialize,Deserialize)]
+#[derive(Serialize,Deserialize,JsonSchema)]
+#[schemars(deny_unknown_fields)]
 struct SetupTemplate {
     label: Option<String>,
-    mapping: HashMap<String,ReferenceTemplate>
+    // spread over all keys
+    image: Option<String>,
+    #[serde(default)]
+    mapping: HashMap<String,ReferenceTemplate>,
+
+    // grid setups: the controls flow over the keys, page by page
+    controls: Option<Vec<String>>,
+    // the keys to use (default: all keys)
+    keys: Option<Vec<String>>,
+    // keys for the generated page buttons (default: bottom left and right,
+    // or the last two grid keys), not keys with a fixed mapping
+    prev: Option<String>,
+    next: Option<String>,
+    prev_image: Option<String>,
+    next_image: Option<String>,
 }
 
-#[derive(Serialize,Deserialize)]
+#[derive(Serialize,Deserialize,JsonSchema)]
+#[schemars(deny_unknown_fields)]
 struct ReferenceTemplate {
     control: String,
     state:   Option<String>
@@ -135,9 +224,16 @@ pub struct ButtonDeckBuilder<D>
     home: Option<PathBuf>,
     midi_in: Option<String>,
     midi_out: Option<String>,
+    serial: Option<String>,
+    hid_path: Option<String>,
     data: Option<D>,
     functions: Vec<(String,ButtonFn<D>)>,
     function_refs: Vec<FnRef>,
+    pub (crate) virtual_device: Option<VirtualDevice>,
+    gesture_timing: GestureTiming,
+    hot_reload: bool,
+    strict_config: bool,
+    unknown_fields: bool,
 }
 
 impl <D> ButtonDeckBuilder<D> 
@@ -154,8 +250,15 @@ impl <D> ButtonDeckBuilder<D>
             home: None,
             midi_in: None,
             midi_out: None,
+            serial: None,
+            hid_path: None,
             functions: Vec::new(),
             function_refs: Vec::new(),
+            virtual_device: None,
+            gesture_timing: GestureTiming::default(),
+            hot_reload: false,
+            strict_config: false,
+            unknown_fields: false,
                 }
     }
 
@@ -174,6 +277,28 @@ impl <D> ButtonDeckBuilder<D>
     }
 
 
+    /// watch the config file, its includes and the image and font files
+    /// of the controls, and reload the setups on a change
+    pub fn with_hot_reload(mut self, enabled: bool) -> Self {
+        self.hot_reload = enabled;
+        self
+    }
+
+    /// refuse to start with a config that has problems (unknown references,
+    /// missing files, ...). Without, the problems are logged as warnings.
+    pub fn with_strict_config(mut self, strict: bool) -> Self {
+        self.strict_config = strict;
+        self
+    }
+
+    /// accept a config with fields the format does not know, they are logged as
+    /// warnings. Without, such a config is refused (e.g. a typo like `xmidi_in`),
+    /// even if it is not strict.
+    pub fn with_unknown_fields(mut self, allow: bool) -> Self {
+        self.unknown_fields = allow;
+        self
+    }
+
     pub fn with_data(mut self, data: D) -> Self {
         self.data = Some(data);
         self
@@ -218,6 +343,45 @@ impl <D> ButtonDeckBuilder<D>
         self
     }
 
+    /// name of the midi input port, overrides `midi_in` from the config
+    pub fn with_midi_in(mut self, port: &str) -> Self {
+        self.midi_in = Some(String::from(port));
+        self
+    }
+
+    /// name of the midi output port, overrides `midi_out` from the config
+    pub fn with_midi_out(mut self, port: &str) -> Self {
+        self.midi_out = Some(String::from(port));
+        self
+    }
+
+    /// bind the deck to the stream deck with this serial number.
+    /// (midi decks are bound by their port, see `with_midi_in`)
+    pub fn with_serial(mut self, serial: &str) -> Self {
+        self.serial = Some(String::from(serial));
+        self
+    }
+
+    /// bind the deck to the stream deck at this hid path (see [`crate::discover`]).
+    /// decks without a serial number can only be opened if no other
+    /// deck of the same model is attached
+    pub fn with_hid_path(mut self, path: &str) -> Self {
+        self.hid_path = Some(String::from(path));
+        self
+    }
+
+    /// thresholds for long press, double tap and auto repeat
+    pub fn with_gesture_timing(mut self, timing: GestureTiming) -> Self {
+        self.gesture_timing = timing;
+        self
+    }
+
+    /// connect to a virtual device instead of real hardware (e.g. for testing)
+    pub fn with_virtual_device(mut self, device: VirtualDevice) -> Self {
+        self.virtual_device = Some(device);
+        self
+    }
+
 
     // pub fn get_button_fn<'a>(&'a self, name: &Option<String>) -> Option<&'a ButtonFn> {
     //     match name  {
@@ -280,6 +444,14 @@ impl <D> ButtonDeckBuilder<D>
             folder: PathBuf::from(self.home_path()),
             ddsetup: Default::default(),
 
+            shutdown: false,
+            pending: Vec::new(),
+
+            gestures: GestureTracker::new(self.gesture_timing),
+            animator: Animator::new(),
+            pages: HashMap::new(),
+            history: Vec::new(),
+            watcher: None,
 
             functions: functionvec,
             // func_refs: function_refs,
@@ -300,16 +472,79 @@ impl <D> ButtonDeckBuilder<D>
 
     }
 
-    pub fn build_for_device(&mut self, device: ButtonDevice) -> Result<DeckDeviceSetup> {
+    pub fn build_for_device(&mut self, deckid: DeckId, device: ButtonDevice) -> Result<DeckDeviceSetup> {
+
+        let (deckjson, unknown) = self.load_config()?;
+
+        build_buttondeck(self, deckid, deckjson, unknown, device)
+
+    }
+
+    /// the config and its includes, if a hot reload is enabled
+    pub (crate) fn watch_paths(&self) -> Option<Vec<PathBuf>> {
+        if !self.hot_reload {
+            return None;
+        }
+        // a file in the working directory has an empty parent
+        let dir = |p: &Path| if p.as_os_str().is_empty() { PathBuf::from(".") } else { p.to_path_buf() };
+
+        let mut paths: Vec<PathBuf> = self.config.iter().cloned().collect();
+        if let Some(c) = &self.config {
+            match read_config::<DeckJson>(c, &mut Vec::new()) {
+                Ok(deckjson) => paths.extend(include::included_files(&deckjson, &dir(c.parent().unwrap_or(Path::new(""))))),
+                Err(e) => warn!("cannot read the includes of {:?}: {:?}", c, e)
+            }
+        }
+        Some(paths)
+    }
 
-        let deckjson: DeckJson = match &self.config {
-            Some(c) => serde_json::from_reader(File::open(c)?)?,
-            None => DeckJson::default()
+    pub (crate) fn kind(&self) -> DeviceKind {
+        self.kind
+    }
+
+    pub (crate) fn serial(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+
+    pub (crate) fn hid_path(&self) -> Option<&str> {
+        self.hid_path.as_deref()
+    }
+
+    /// midi in/out port names: builder first, then the config, then the device defaults
+    pub (crate) fn midi_ports(&self) -> (Option<String>,Option<String>) {
+
+        let deckjson = match self.load_config() {
+            Ok((dj,_)) => dj,
+            Err(e) => {
+                warn!("cannot read midi ports from config: {:?}", e);
+                DeckJson::default()
+            }
         };
 
+        let specs = self.kind.get_specs();
+
+        let midi_in = self.midi_in.clone()
+            .or(deckjson.midi_in)
+            .or(specs.midi_in);
 
-        build_buttondeck(self, deckjson, device)
+        let midi_out = self.midi_out.clone()
+            .or(deckjson.midi_out)
+            .or(specs.midi_out);
 
+        (midi_in, midi_out)
+    }
+
+    // the config and its unknown fields
+    fn load_config(&self) -> Result<(DeckJson,Vec<ConfigProblem>)> {
+        let mut unknown = Vec::new();
+        match &self.config {
+            Some(c) => {
+                let mut deckjson = read_config(c, &mut unknown)?;
+                include::resolve_config(&mut deckjson, c.parent().unwrap_or(Path::new("")), &mut unknown)?;
+                Ok((deckjson, unknown))
+            },
+            None => Ok((DeckJson::default(), unknown))
+        }
     }
 
 
@@ -415,6 +650,15 @@ impl<'a,D> BuilderData<'a,D>
     where D: Send + Sync
 {
 
+    // "back" and "home" are built in, everything else is the name of a setup
+    fn target_for_opt_name(&self, name: &Option<String>) -> Option<SetupTarget> {
+        match name.as_deref() {
+            Some(SETUP_BACK) => Some(SetupTarget::Back),
+            Some(SETUP_HOME) => Some(SetupTarget::Home),
+            _ => self.setup_for_opt_name(name).map(SetupTarget::Setup)
+        }
+    }
+
     fn setup_for_opt_name(&self, name: &Option<String>) -> Option<SetupId> {
         match name {
             Some(s) => {
@@ -491,39 +735,43 @@ struct Prep<'a,R,T> {
 
 
 
-fn  build_buttondeck<D: Send + Sync>(builder: &mut ButtonDeckBuilder<D>, mut deckjson: DeckJson, any_device: ButtonDevice /* , functions: Vec<ButtonFn>, path: P */)  -> Result<DeckDeviceSetup> {
+fn  build_buttondeck<D: Send + Sync>(builder: &mut ButtonDeckBuilder<D>, deckid: DeckId, mut deckjson: DeckJson, mut unknown: Vec<ConfigProblem>, mut any_device: ButtonDevice /* , functions: Vec<ButtonFn>, path: P */)  -> Result<DeckDeviceSetup> {
 
-    let deckid = DeckId { index: idgen.fetch_add(1, Ordering::SeqCst) };
     builder.home = deckjson.assets.map(|s| PathBuf::from(s));
 
     // debug!("setup::build_buttondeck {:?} with dir {:?}", &device.model(), home_folder);
-    let device: &dyn ButtonDeviceTrait = match &any_device {
-        ButtonDevice::Streamdeck(sd) => sd as &dyn ButtonDeviceTrait,
-        ButtonDevice::Midi(md) => md,
-    };
+    let device: &dyn ButtonDeviceTrait = any_device.as_trait();
 
     let model = device.model();
+    let serial = device.serial();
 
-    info!("build_buttondeck for device {}", model);
+    info!("build_buttondeck for device {} ({:?})", model, serial);
 
+    // a template for this very device (by serial or port), then one for the model
     let opt_template = deckjson.devices
-        .and_then(|mut dv| dv.remove(&model))
-        .or_else(|| deckjson.deck);
+        .and_then(|mut dv| {
+            let key = serial.clone().filter(|s| dv.contains_key(s)).unwrap_or_else(|| model.clone());
+            dv.remove(&key).map(|t| (t, format!("devices.{}.", key)))
+        })
+        .or_else(|| deckjson.deck.map(|t| (t, String::from("deck."))));
 
 
 
-    let device_template = match opt_template {
+    let (device_template, template_path) = match opt_template {
 
         Some(t) => t,
         None => {
 
-            let json_path = builder.home_path().join(format!("{}.json", device.model()));
+            let json_path = find_config(builder.home_path(), &device.model());
             trace!("Reading config from {:?}", json_path);
         
-            let f = File::open(json_path)?;
-            let xt: ButtonDeckTemplate = serde_json::from_reader(f)?;
+            let mut file_unknown = Vec::new();
+            let mut xt: ButtonDeckTemplate = read_config(&json_path, &mut file_unknown)?;
+            include::resolve_template(&mut xt, builder.home_path(), &mut Vec::new(), &mut file_unknown)?;
             trace!("Done reading config");
-            xt
+            let file = format!("{}:", json_path.file_name().map(|f| f.to_string_lossy()).unwrap_or_default());
+            unknown.extend(file_unknown.into_iter().map(|p| ConfigProblem { path: format!("{}{}", file, p.path), ..p }));
+            (xt, file)
 
         }
 
@@ -535,24 +783,48 @@ fn  build_buttondeck<D: Send + Sync>(builder: &mut ButtonDeckBuilder<D>, mut dec
     // Wiring
     // --------------------------------------------------------
 
-    let wiring = device_template.wiring;
+    let specs = any_device.specs();
+    let layout = specs.key_layout();
+
+    // keys of the device grid, replaced by the explicit wiring
+    let mut generated = generate_wiring(&specs);
+
+    // broken keys and duplicate ids are reported by the validation
+    let mut explicit = Vec::new();
+    for (n,pt) in &device_template.wiring {
+        if let Ok(k) = pt.into_key(n) {
+            explicit.push(k);
+        }
+    }
+    generated.retain(|(_,g)| !explicit.iter().any(|e| e.id == g.id || e.name == g.name));
 
-    let maxid = wiring.iter().map(|(n,w)| w.id).max().unwrap_or(127);
+    let maxid = explicit.iter().chain(generated.iter().map(|(_,g)| g))
+        .map(|p| p.id).max().unwrap_or(127);
     trace!("Max button id is {}", maxid);
 
     let mut phys: Vec<Option<PhysicalKey>> = vec![None;maxid+1];
     let mut phymap: HashMap<String,PhysicalKey> = HashMap::new();
 
-    for (n,pt) in &wiring {
-        let p: PhysicalKey = pt.into_key(n)?;
-        if phys[p.id].is_some() { return  Err(DeckError::Message(format!("duplicate id: {}", p.id)));} 
+    for p in explicit {
+        if phys[p.id].is_some() { continue; }
         // if phymap.contains_key(&p.name) { return  Err(DeckError::Message(format!("duplicate name: {}", p.name))); }
 
         trace!("Physical Key: {:?}", p);
         phys[p.id] = Some(p.clone());
-        phymap.insert(n.clone(), p);
+        phymap.insert(p.name.clone(), p);
     }
 
+    for (alias,p) in generated {
+        trace!("Generated Key: {:?}", p);
+        phys[p.id] = Some(p.clone());
+        if let Some(a) = alias {
+            phymap.entry(a).or_insert_with(|| p.clone());
+        }
+        phymap.insert(p.name.clone(), p);
+    }
+
+    any_device.as_trait_mut().set_wiring(&phys);
+
 
 
 
@@ -560,15 +832,50 @@ fn  build_buttondeck<D: Send + Sync>(builder: &mut ButtonDeckBuilder<D>, mut dec
     // Setups
     // --------------------------------------------------------
 
-    // collect all setup templates
-    let setups = device_template.setups
-        .or_else(|| deckjson.setups)
-        .unwrap_or_else(|| IndexMap::new());
+    // where things are in the json, for the problem reports
+    let names = |part: &str, m: Option<Vec<&String>>| m.unwrap_or_default().into_iter().map(|n| format!("{}.{}", part, n)).collect::<Vec<_>>();
+    let paths = ConfigPaths::new(&template_path, [
+            names("controls", device_template.controls.as_ref().map(|m| m.keys().collect())),
+            names("setups", device_template.setups.as_ref().map(|m| m.keys().collect())),
+            names("grids", device_template.grids.as_ref().map(|m| m.keys().collect())),
+        ].concat().into_iter().collect());
+
+    // the entries of the device section win over those of the config
+    let mut setups = include::over(device_template.setups, deckjson.setups);
+    // grids are setups too
+    let grids = include::over(device_template.grids, deckjson.grids);
+    let mut controls = include::over(device_template.controls, deckjson.controls);
+
+    // the page buttons of the grids, the names are reserved
+    let mut reserved = Vec::new();
+    for (gn,gt) in &grids {
+        for (n, step, text, image) in [("prev", -1, "<", &gt.prev_image), ("next", 1, ">", &gt.next_image)] {
+            let name = format!("{}.{}", gn, n);
+            if controls.contains_key(&name) {
+                reserved.push(ConfigProblem { path: paths.entry("controls", &name), message: format!("'{}' is reserved for the page button of grid '{}'", name, gn) });
+                continue;
+            }
+            controls.insert(name, page_button(step, text, image));
+        }
+    }
+
+    let refuse = builder.strict_config || (!unknown.is_empty() && !builder.unknown_fields);
+
+    let problems = {
+        let functions = builder.function_refs.iter().map(|f| f.name.as_str()).collect();
+        let setup_names = setups.keys().chain(grids.keys()).map(|s| s.as_str()).collect();
+        let mut v = Validator::new(builder.home_path(), &phymap, &controls, setup_names, functions);
+        v.wiring(&paths.of("wiring"), &device_template.wiring);
+        v.controls(&paths);
+        v.setups(&paths, "setups", &setups);
+        v.setups(&paths, "grids", &grids);
+        unknown.into_iter().chain(reserved).chain(v.problems()).collect()
+    };
+    check_problems(refuse, problems)?;
 
-    // collect all button (control) templates
-    let controls = device_template.controls
-        .or_else(|| deckjson.controls)
-        .unwrap_or_else(|| IndexMap::new());
+    let grid_names: Vec<String> = grids.keys().cloned().collect();
+    let grids_path = |n: &str| paths.entry(if grid_names.iter().any(|g| g == n) { "grids" } else { "setups" }, n);
+    setups.extend(grids);
 
     // build 'prep' structs (name, reference, template) for setups
     let setup_refs: Vec<Prep<SetupId,SetupTemplate>> = setups.iter().enumerate()
@@ -603,9 +910,16 @@ fn  build_buttondeck<D: Send + Sync>(builder: &mut ButtonDeckBuilder<D>, mut dec
     };
 
 
-    let button_arena: Vec<Button> = data.button_refs.iter().enumerate()
-        .filter_map(|(i,p)| build_button(&data, i).ok())
+    let button_arena: Vec<Button> = (0..data.button_refs.len())
+        .map(|i| build_button(&data, i))
+        .collect::<Result<_>>()?;
+
+    // convert the images now, not on the first setup switch
+    let images: Vec<ButtonImage> = button_arena.iter()
+        .flat_map(|b| b.images())
+        .cloned()
         .collect();
+    any_device.as_trait_mut().preload_images(&images);
 
 
     // this is where all buttons live
@@ -617,6 +931,8 @@ fn  build_buttondeck<D: Send + Sync>(builder: &mut ButtonDeckBuilder<D>, mut dec
 
 //    let setup_map: HashMap<String,ButtonSetup> = HashMap::new();
     let mut setup_arena: Vec<ButtonSetup> = Vec::new(); // HashMap::new();
+    let mut panel_files = Vec::new();
+    let mut grid_problems = Vec::new();
         // template.setups.iter()
     //     .map(|(n,t)| (String::from(n),build_button_setup(&button_map, n,t)) )
     //     .collect();
@@ -663,11 +979,43 @@ fn  build_buttondeck<D: Send + Sync>(builder: &mut ButtonDeckBuilder<D>, mut dec
 
         }
     
+        let grid = match &st.controls {
+            Some(c) => match build_grid(prep.name, st, c, &mapping, &button_map, &phys, &phymap, layout) {
+                Ok(g) => Some(g),
+                Err(e) => {
+                    grid_problems.push(ConfigProblem { path: grids_path(prep.name), message: e.to_string() });
+                    None
+                }
+            },
+            None => None
+        };
+
         // setup_map.insert(sn.clone(), ButtonSetup { name: sn.clone(), mapping });
-        setup_arena.push(ButtonSetup { id: prep.reference.clone(),  name: String::from(prep.name), mapping});
+        if let Some(img) = &st.image {
+            panel_files.push(builder.home_path().join(img));
+        }
+        let panel = match (&st.image, layout) {
+            (Some(img), Some(layout)) => match ButtonImage::from_file(builder.home_path().join(img)).load() {
+                Ok(i) => slice_panel(&i, &layout),
+                Err(e) => {
+                    warn!("cannot load panel image {}: {:?}", img, e);
+                    Vec::new()
+                }
+            },
+            (Some(img), None) => {
+                warn!("panel image {} needs a device with a key grid", img);
+                Vec::new()
+            },
+            _ => Vec::new()
+        };
+
+        setup_arena.push(ButtonSetup { id: prep.reference.clone(),  name: String::from(prep.name), mapping, panel, grid});
 
     }
 
+    // what the validation cannot see, e.g. too few keys for a grid
+    check_problems(builder.strict_config, grid_problems)?;
+
 
     let ccm: Vec<Option<ButtonMapping>> = phys.iter().map(|_| None).collect();
 
@@ -688,6 +1036,10 @@ fn  build_buttondeck<D: Send + Sync>(builder: &mut ButtonDeckBuilder<D>, mut dec
         wiring: phys,
         setup_arena,
         current_setup: 0,
+        specs,
+        model,
+        serial,
+        panel_files,
     })
 
 //     Ok(ButtonDeck {
@@ -734,6 +1086,249 @@ fn state_for_opt_name(data: &Vec<Prep<StateId,StateTemplate>>, name: &Option<Str
     
 }   
 
+// config files are found with these extensions, in this order
+const CONFIG_EXTENSIONS: &[&str] = &["json", "json5", "jsonc", "toml", "yaml", "yml"];
+
+// the config file for a device model, in any of the formats
+fn find_config(folder: &Path, model: &str) -> PathBuf {
+    CONFIG_EXTENSIONS.iter()
+        .map(|e| folder.join(format!("{}.{}", model, e)))
+        .find(|p| p.exists())
+        .unwrap_or_else(|| folder.join(format!("{}.json", model)))
+}
+
+// parse a config file, the format is chosen by the extension:
+// json, json5 or jsonc (json with comments), toml, yaml or yml.
+// Unknown fields are collected here, the builder decides whether to refuse them.
+fn read_config<T: DeserializeOwned>(path: &Path, unknown: &mut Vec<ConfigProblem>) -> Result<T> {
+
+    let report = |p: serde_ignored::Path| {
+        // '?' are the options on the way
+        let path: Vec<String> = p.to_string().split('.').filter(|s| *s != "?").map(String::from).collect();
+        unknown.push(ConfigProblem { path: path.join("."), message: String::from("unknown field") });
+    };
+
+    let ext = path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+
+    debug!("read config {:?} ({})", path, ext);
+
+    let t = match ext.as_str() {
+        "toml" => {
+            let s = std::fs::read_to_string(path)?;
+            serde_ignored::deserialize(toml::Deserializer::new(&s), report)?
+        },
+        "yaml" | "yml" => {
+            let f = std::io::BufReader::new(File::open(path)?);
+            serde_ignored::deserialize(serde_yaml::Deserializer::from_reader(f), report)?
+        },
+        "json5" | "jsonc" => {
+            let s = std::fs::read_to_string(path)?;
+            let mut de = json5::Deserializer::from_str(&s)?;
+            serde_ignored::deserialize(&mut de, report)?
+        },
+        _ => {
+            let mut de = serde_json::Deserializer::from_reader(std::io::BufReader::new(File::open(path)?));
+            let t = serde_ignored::deserialize(&mut de, report)?;
+            de.end()?;
+            t
+        }
+    };
+
+    Ok(t)
+}
+
+/// The JSON Schema of a config file (the one given to `with_config`)
+pub fn config_schema() -> Value {
+    serde_json::to_value(schemars::schema_for!(DeckJson)).unwrap_or(Value::Null)
+}
+
+/// The JSON Schema of a config for one device model (e.g. `Stream Deck Mini.json`)
+pub fn device_config_schema() -> Value {
+    serde_json::to_value(schemars::schema_for!(ButtonDeckTemplate)).unwrap_or(Value::Null)
+}
+
+// strict: refuse the config, lenient: just tell
+fn check_problems(strict: bool, problems: Vec<ConfigProblem>) -> Result<()> {
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    for p in &problems {
+        if strict {
+            error!("config: {}", p);
+        } else {
+            warn!("config: {}", p);
+        }
+    }
+
+    if strict {
+        Err(DeckError::InvalidConfig(problems))
+    } else {
+        Ok(())
+    }
+}
+
+fn page_button(step: i32, text: &str, image: &Option<String>) -> ButtonTemplate {
+    ButtonTemplate {
+        label: Some(String::from(text)),
+        color: Some(String::from("#202020")),
+        text: if image.is_some() { None } else { Some(String::from("{label}")) },
+        image: image.clone(),
+        page: Some(step),
+        ..Default::default()
+    }
+}
+
+// the controls of a grid setup and the keys they flow over
+fn build_grid(name: &str, st: &SetupTemplate, controls: &[String], mapping: &[ButtonMapping], button_map: &HashMap<String,ButtonId>, 
+    phys: &[Option<PhysicalKey>], phymap: &HashMap<String,PhysicalKey>, layout: Option<KeyLayout>) -> Result<SetupGrid>
+{
+
+    let key_by_name = |n: &String| phymap.get(n).cloned()
+        .ok_or_else(|| DeckError::InvalidKey(format!("grid {}: no key {}", name, n)));
+
+    let mut keys: Vec<PhysicalKey> = match &st.keys {
+        Some(names) => names.iter().map(key_by_name).collect::<Result<_>>()?,
+        None => phys.iter().flatten().filter(|p| p.kind == KeyKind::Key).cloned().collect()
+    };
+
+    // keys with a fixed mapping are not part of the grid
+    keys.retain(|k| !mapping.iter().any(|m| m.key.id == k.id));
+
+    let button = |n: &str| button_map.get(n).copied()
+        .ok_or_else(|| DeckError::Message(format!("grid {}: no control {}", name, n)));
+
+    let controls: Vec<ButtonId> = controls.iter().map(|c| button(c)).collect::<Result<_>>()?;
+
+    // all controls fit, no pages
+    if controls.len() <= keys.len() {
+        return Ok(SetupGrid { controls, keys, page_keys: None });
+    }
+
+    let pinned = |field: &str, n: &String| -> Result<PhysicalKey> {
+        let k = key_by_name(n)?;
+        if mapping.iter().any(|m| m.key.id == k.id) {
+            return Err(DeckError::Message(format!("grid {}: {} key {} has a fixed control", name, field, n)))
+        }
+        Ok(k)
+    };
+    let prev = st.prev.as_ref().map(|n| pinned("prev", n)).transpose()?;
+    let next = st.next.as_ref().map(|n| pinned("next", n)).transpose()?;
+
+    // by default bottom right and bottom left of the device if they are
+    // grid keys, else the last grid keys
+    let free = |k: &PhysicalKey, other: &Option<PhysicalKey>| keys.iter().any(|g| g.id == k.id)
+        && other.as_ref().map(|o| o.id != k.id).unwrap_or(true);
+    let pick = |corner: Option<usize>, other: &Option<PhysicalKey>| corner
+        .and_then(|n| phys.get(n).cloned().flatten())
+        .filter(|k| free(k, other))
+        .or_else(|| keys.iter().rev().find(|k| free(k, other)).cloned());
+    let next = next.or_else(|| pick(layout.map(|l| l.first_key + l.rows * l.cols - 1), &prev));
+    let prev = prev.or_else(|| pick(layout.map(|l| l.first_key + (l.rows - 1) * l.cols), &next));
+
+    let (prev, next) = match (prev, next) {
+        (Some(p), Some(n)) if p.id != n.id => (p, n),
+        _ => return Err(DeckError::Message(format!("grid {}: not enough keys for the page buttons", name)))
+    };
+    keys.retain(|k| k.id != prev.id && k.id != next.id);
+
+    // at least one key per page for the controls
+    if keys.is_empty() {
+        return Err(DeckError::Message(format!("grid {}: no keys left for the controls next to the page buttons", name)))
+    }
+
+    Ok(SetupGrid {
+        controls,
+        keys,
+        page_keys: Some([
+            (prev, button(&format!("{}.prev", name))?),
+            (next, button(&format!("{}.next", name))?),
+        ]),
+    })
+}
+
+// wiring from the device geometry: the keys are named A1, B1, .. by column
+// letter and row number (with R1C1, R1C2, .. as alias), dials and touch
+// strip segments of a stream deck plus are DIAL1.. and STRIP1..
+// Grids wider than 26 columns only have the R1C1 names.
+fn generate_wiring(specs: &DeviceSpecs) -> Vec<(Option<String>,PhysicalKey)> {
+
+    let mut keys = Vec::new();
+
+    for n in 0..specs.key_count() {
+        let (r,c) = (n / specs.cols, n % specs.cols);
+        let rc = format!("R{}C{}", r+1, c+1);
+        let (alias, name) = match specs.cols {
+            1..=26 => (Some(rc), format!("{}{}", (b'A' + c as u8) as char, r+1)),
+            _ => (None, rc)
+        };
+        let id = specs.key_id(n);
+        keys.push((alias, PhysicalKey {
+            id,
+            name,
+            // buttons that send a controller
+            kind: if id >= MIDI_CC_BASE { KeyKind::CcKey } else { KeyKind::Key },
+            on_off_threshold: None,
+        }));
+    }
+
+    if specs.family == DeviceFamily::Streamdeck {
+        let dial_base = specs.first_key + specs.key_count();
+        for n in 0..specs.encoders {
+            keys.push((None, PhysicalKey {
+                id: dial_base + n,
+                name: format!("DIAL{}", n+1),
+                kind: KeyKind::Dial,
+                on_off_threshold: None,
+            }));
+            if specs.touchscreen {
+                keys.push((None, PhysicalKey {
+                    id: dial_base + specs.encoders + n,
+                    name: format!("STRIP{}", n+1),
+                    kind: KeyKind::Touch,
+                    on_off_threshold: None,
+                }));
+            }
+        }
+    }
+
+    keys
+}
+
+fn effect(effect: &Option<String>) -> Option<Effect> {
+    effect.as_ref().and_then(|e| match Effect::from_str(e) {
+        Ok(e) => Some(e),
+        Err(e) => {
+            warn!("{}", e);
+            None
+        }
+    })
+}
+
+fn overlay_images(folder: &Path, overlays: &Option<Vec<String>>) -> Option<Vec<ButtonImage>> {
+    overlays.as_ref().map(|o| o.iter().map(|f| ButtonImage::from_file(folder.join(f))).collect())
+}
+
+fn button_text(folder: &Path, text: &Option<String>, font: &Option<String>, size: Option<f32>, align: &Option<String>, color: &Option<String>) -> ButtonText {
+    ButtonText {
+        text: text.clone(),
+        font: font.as_ref().map(|f| folder.join(f)),
+        size,
+        align: align.as_ref().and_then(|a| match TextAlign::from_str(a) {
+            Ok(a) => Some(a),
+            Err(e) => {
+                warn!("{}", e);
+                None
+            }
+        }),
+        color: ButtonColor::from_option_string(color),
+    }
+}
+
 // fn build_button(data: &BuilderData, n: &str, bt: &ButtonTemplate) -> Button {
 fn build_button<D: Send + Sync>(data: &BuilderData<D>, index: usize) -> Result<Button> {
 
@@ -765,14 +1360,25 @@ fn build_button<D: Send + Sync>(data: &BuilderData<D>, index: usize) -> Result<B
 
         color: ButtonColor::from_option_string(&bt.color), 
         image: ButtonImage::from_option_string(&data.builder.home_path(), &bt.image),
+        overlays: overlay_images(data.builder.home_path(), &bt.overlays),
+        effect: effect(&bt.effect),
 
         value: ButtonValue::from(bt.value.clone()),
 
+        text: button_text(data.builder.home_path(), &bt.text, &bt.font, bt.font_size, &bt.text_align, &bt.text_color),
+
         on_button_down: data.get_button_fn_ref(&bt.on_down).cloned(), 
         on_button_up: data.get_button_fn_ref(&bt.on_up).cloned(), 
+        on_value: data.get_button_fn_ref(&bt.on_value).cloned(), 
+
+        on_long_press: data.get_button_fn_ref(&bt.on_long_press).cloned(),
+        on_double_tap: data.get_button_fn_ref(&bt.on_double_tap).cloned(),
+        long_press_setup: data.target_for_opt_name(&bt.long_press_setup),
+        repeat: bt.repeat,
         
         switch_button_state: state_for_opt_name(&state_prep, &bt.switch_button_state),
-        switch_deck_setup: data.setup_for_opt_name(&bt.switch_deck_setup),
+        switch_deck_setup: data.target_for_opt_name(&bt.switch_deck_setup),
+        page: bt.page,
 
     };
     
@@ -784,11 +1390,20 @@ fn build_button<D: Send + Sync>(data: &BuilderData<D>, index: usize) -> Result<B
             name: String::from("default"),
             color: Default::default(), 
             image: Default::default(), 
+            overlays: Default::default(),
+            effect: Default::default(),
             value: Default::default(), 
+            text: Default::default(),
             on_button_down: Default::default(), 
             on_button_up: Default::default(), 
+            on_value: Default::default(), 
+            on_long_press: Default::default(),
+            on_double_tap: Default::default(),
+            long_press_setup: Default::default(),
+            repeat: Default::default(),
             switch_button_state: Default::default(), 
-            switch_deck_setup: Default::default() 
+            switch_deck_setup: Default::default(),
+            page: Default::default(),
         } ]
     } else {
         state_prep.iter()
@@ -798,11 +1413,20 @@ fn build_button<D: Send + Sync>(data: &BuilderData<D>, index: usize) -> Result<B
                     name: String::from(p.name),
                     color: ButtonColor::from_option_string(&p.template.color), 
                     image: ButtonImage::from_option_string(data.builder.home_path(), &p.template.image), 
+                    overlays: overlay_images(data.builder.home_path(), &p.template.overlays),
+                    effect: effect(&p.template.effect),
                     value: ButtonValue::from(p.template.value.clone()),
+                    text: button_text(data.builder.home_path(), &p.template.text, &p.template.font, p.template.font_size, &p.template.text_align, &p.template.text_color),
                     on_button_down: data.get_button_fn_ref(&p.template.on_down).cloned(), 
                     on_button_up: data.get_button_fn_ref(&p.template.on_up).cloned(),
+                    on_value: data.get_button_fn_ref(&p.template.on_value).cloned(),
+                    on_long_press: data.get_button_fn_ref(&p.template.on_long_press).cloned(),
+                    on_double_tap: data.get_button_fn_ref(&p.template.on_double_tap).cloned(),
+                    long_press_setup: data.target_for_opt_name(&p.template.long_press_setup),
+                    repeat: p.template.repeat,
                     switch_button_state: state_for_opt_name(&state_prep, &p.template.switch_button_state), //  s.switch_button_state.clone(),
-                    switch_deck_setup: data.setup_for_opt_name(&p.template.switch_deck_setup),
+                    switch_deck_setup: data.target_for_opt_name(&p.template.switch_deck_setup),
+                    page: p.template.page,
                 };
                 debug!("Button State: {:?}", bs);
                 bs
@@ -828,3 +1452,80 @@ fn build_button<D: Send + Sync>(data: &BuilderData<D>, index: usize) -> Result<B
 
 }
 
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const FORMATS: &[&str] = &["json", "json5", "toml", "yaml"];
+
+    fn manifest_dir() -> &'static Path {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+    }
+
+    #[test]
+    fn config_formats() {
+
+        let read = |ext: &str| {
+            let mut unknown = Vec::new();
+            let path = manifest_dir().join("tests").join("configs").join("formats").join(format!("stream_deck_mini.{}", ext));
+            let t: ButtonDeckTemplate = read_config(&path, &mut unknown).unwrap();
+            assert!(unknown.is_empty(), "{}: {:?}", ext, unknown);
+            serde_json::to_value(t).unwrap()
+        };
+
+        let json = read("json");
+        for ext in FORMATS {
+            assert_eq!(read(ext), json, "stream_deck_mini.{}", ext);
+        }
+    }
+
+    // build a config of tests/configs on a virtual device, the paths of the
+    // problems if it is refused, nothing if it builds
+    fn problem_paths<F>(config: &str, keys: usize, more: F) -> Vec<String>
+        where F: FnOnce(ButtonDeckBuilder<()>) -> ButtonDeckBuilder<()>
+    {
+        let (device, _handle) = VirtualDevice::new("virtual", keys);
+        let mut builder = more(ButtonDeckBuilder::<()>::new(DeviceKind::StreamDeck))
+            .with_config(manifest_dir().join("tests").join("configs").join(config));
+
+        match builder.build_for_device(DeckId { index: 0 }, ButtonDevice::Virtual(device)) {
+            Err(DeckError::InvalidConfig(problems)) => problems.into_iter().map(|p| p.path).collect(),
+            Err(e) => panic!("{:?}", e),
+            Ok(_) => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn reserved_page_button() {
+        let paths = problem_paths("reserved.json", 3, |b| b.with_strict_config(true));
+        assert_eq!(paths, ["deck.controls.two.next"]);
+    }
+
+    #[test]
+    fn grid_page_keys() {
+        // a page button on a fixed control, and no key left for the controls
+        let paths = problem_paths("page_keys.json", 3, |b| b.with_strict_config(true));
+        assert_eq!(paths, ["deck.grids.pinned", "deck.grids.small"]);
+    }
+
+    #[test]
+    fn refuse_unknown_fields() {
+        // refused even without strict config
+        assert_eq!(problem_paths("typo.json", 1, |b| b), ["xmidi_in"]);
+        assert!(problem_paths("typo.json", 1, |b| b.with_unknown_fields(true)).is_empty());
+    }
+
+    #[test]
+    fn unknown_fields() {
+        for ext in FORMATS {
+            let mut unknown = Vec::new();
+            let path = manifest_dir().join("tests").join("configs").join("formats").join(format!("unknown.{}", ext));
+            let _: ButtonDeckTemplate = read_config(&path, &mut unknown).unwrap();
+            let paths: Vec<&str> = unknown.iter().map(|p| p.path.as_str()).collect();
+            assert_eq!(paths, ["colour", "controls.c.colr"], "unknown.{}", ext);
+        }
+    }
+}
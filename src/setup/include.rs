@@ -0,0 +1,227 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use indexmap::IndexMap;
+use log::debug;
+
+use crate::{ConfigProblem, DeckError};
+
+use super::{ButtonDeckTemplate, DeckJson, Result, read_config};
+
+
+// Includes are merged by name (controls, setups, grids, wiring):
+//
+// * the including file wins over everything it includes
+// * a later include wins over an earlier one
+// * a device section (with its includes) wins over the top level of the config
+// * included files may include other files, paths are relative to the including file
+// * image files are found in the assets folder, as for the main config
+//
+// The entries of the including file come first, so its first setup stays the default.
+
+
+/// resolve the includes of the config and of its device sections
+pub (super) fn resolve_config(config: &mut DeckJson, dir: &Path, problems: &mut Vec<ConfigProblem>) -> Result<()> {
+
+    let mut stack = Vec::new();
+
+    if let Some(includes) = config.include.take() {
+
+        let libs = load_all(&includes, dir, &mut stack, problems)?;
+
+        for (n, lib) in libs.iter().enumerate() {
+            if !lib.wiring.is_empty() {
+                problems.push(ConfigProblem {
+                    path: format!("include[{}]", n),
+                    message: String::from("wiring is only included in device sections"),
+                });
+            }
+        }
+
+        let (controls, setups, grids) = split(libs);
+        merge_opt(&mut config.controls, controls);
+        merge_opt(&mut config.setups, setups);
+        merge_opt(&mut config.grids, grids);
+    }
+
+    for t in config.devices.iter_mut().flat_map(|d| d.values_mut()).chain(config.deck.iter_mut()) {
+        resolve_template(t, dir, &mut stack, problems)?;
+    }
+
+    Ok(())
+}
+
+/// resolve the includes of a device section (or of a config file for one device)
+pub (super) fn resolve_template(template: &mut ButtonDeckTemplate, dir: &Path, stack: &mut Vec<PathBuf>, problems: &mut Vec<ConfigProblem>) -> Result<()> {
+
+    let includes = match template.include.take() {
+        Some(i) => i,
+        None => return Ok(())
+    };
+
+    let mut libs = load_all(&includes, dir, stack, problems)?;
+
+    let wiring: Vec<_> = libs.iter_mut().map(|l| std::mem::take(&mut l.wiring)).collect();
+    merge(&mut template.wiring, wiring);
+
+    let (controls, setups, grids) = split(libs);
+    merge_opt(&mut template.controls, controls);
+    merge_opt(&mut template.setups, setups);
+    merge_opt(&mut template.grids, grids);
+
+    Ok(())
+}
+
+
+// the included files, their own includes resolved
+fn load_all(includes: &[String], dir: &Path, stack: &mut Vec<PathBuf>, problems: &mut Vec<ConfigProblem>) -> Result<Vec<ButtonDeckTemplate>> {
+
+    let mut libs = Vec::new();
+
+    for inc in includes {
+
+        let path = dir.join(inc);
+        let canonical = path.canonicalize()
+            .map_err(|e| DeckError::Message(format!("cannot include {:?}: {}", path, e)))?;
+
+        if stack.contains(&canonical) {
+            return Err(DeckError::Message(format!("include cycle: {:?} includes itself", path)));
+        }
+
+        debug!("include {:?}", path);
+
+        let mut unknown = Vec::new();
+        let mut lib: ButtonDeckTemplate = read_config(&path, &mut unknown)?;
+        problems.extend(unknown.into_iter().map(|p| ConfigProblem { path: format!("{}:{}", inc, p.path), ..p }));
+
+        stack.push(canonical);
+        let lib_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        resolve_template(&mut lib, &lib_dir, stack, problems)?;
+        stack.pop();
+
+        libs.push(lib);
+    }
+
+    Ok(libs)
+}
+
+type Maps<T> = Vec<Option<IndexMap<String,T>>>;
+
+fn split(libs: Vec<ButtonDeckTemplate>) -> (Maps<super::ButtonTemplate>, Maps<super::SetupTemplate>, Maps<super::SetupTemplate>) {
+    let mut controls = Vec::new();
+    let mut setups = Vec::new();
+    let mut grids = Vec::new();
+    for l in libs {
+        controls.push(l.controls);
+        setups.push(l.setups);
+        grids.push(l.grids);
+    }
+    (controls, setups, grids)
+}
+
+// add the entries of the libraries (in order) that `into` does not have itself
+fn merge<T>(into: &mut IndexMap<String,T>, libs: Vec<IndexMap<String,T>>) {
+    let own: HashSet<String> = into.keys().cloned().collect();
+    for lib in libs {
+        for (k, v) in lib {
+            if !own.contains(&k) {
+                into.insert(k, v);
+            }
+        }
+    }
+}
+
+/// the controls, setups or grids of a device section over those of the config
+pub (super) fn over<T>(section: Option<IndexMap<String,T>>, config: Option<IndexMap<String,T>>) -> IndexMap<String,T> {
+    let mut into = section.unwrap_or_default();
+    merge(&mut into, config.into_iter().collect());
+    into
+}
+
+// like merge, but a missing map stays missing if no library has one
+// (a device section without controls uses the ones of the config)
+fn merge_opt<T>(into: &mut Option<IndexMap<String,T>>, libs: Maps<T>) {
+    let libs: Vec<_> = libs.into_iter().flatten().collect();
+    if !libs.is_empty() {
+        merge(into.get_or_insert_with(IndexMap::new), libs);
+    }
+}
+
+/// the files included by the config, directly or by other included files
+/// (for the hot reload). Files that cannot be read are left out.
+pub (super) fn included_files(config: &DeckJson, dir: &Path) -> Vec<PathBuf> {
+
+    let mut files = Vec::new();
+
+    let sections = config.devices.iter().flat_map(|d| d.values()).chain(config.deck.iter());
+    for includes in config.include.iter().chain(sections.filter_map(|t| t.include.as_ref())) {
+        collect(includes, dir, &mut files);
+    }
+
+    files
+}
+
+fn collect(includes: &[String], dir: &Path, files: &mut Vec<PathBuf>) {
+    for inc in includes {
+        let path = dir.join(inc);
+        if files.contains(&path) {
+            continue;
+        }
+        files.push(path.clone());
+        if let Ok(lib) = read_config::<ButtonDeckTemplate>(&path, &mut Vec::new()) {
+            if let Some(nested) = &lib.include {
+                let lib_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+                collect(nested, &lib_dir, files);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use super::super::ButtonTemplate;
+
+    fn dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("configs").join("include")
+    }
+
+    fn load(name: &str) -> Result<DeckJson> {
+        let mut config: DeckJson = read_config(&dir().join(name), &mut Vec::new())?;
+        resolve_config(&mut config, &dir(), &mut Vec::new())?;
+        Ok(config)
+    }
+
+    // name and color of the controls
+    fn colors(controls: &IndexMap<String,ButtonTemplate>) -> Vec<(&str,&str)> {
+        controls.iter().map(|(n,c)| (n.as_str(), c.color.as_deref().unwrap_or_default())).collect()
+    }
+
+    #[test]
+    fn includes() {
+        let config = load("main.json").unwrap();
+        // own entries first and winning, the later include wins, nested paths are relative to the include
+        assert_eq!(colors(config.controls.as_ref().unwrap()), [
+            ("own", "#000001"), ("shared", "#000001"), ("both", "#222222"), ("first", "#111111"), ("deep", "#333333"),
+        ]);
+    }
+
+    #[test]
+    fn device_section() {
+        let mut config = load("main.json").unwrap();
+        let mini = config.devices.as_mut().and_then(|d| d.remove("mini")).unwrap();
+        let controls = over(mini.controls, config.controls);
+        // the device section and its includes win, the other controls of the config stay
+        assert_eq!(colors(&controls), [
+            ("shared", "#000003"), ("both", "#222222"), ("own", "#000001"), ("first", "#111111"), ("deep", "#333333"),
+        ]);
+    }
+
+    #[test]
+    fn cycle() {
+        let e = load("cycle.json").err().map(|e| e.to_string()).unwrap_or_default();
+        assert!(e.contains("include cycle"), "{}", e);
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::str::FromStr;
+
+use indexmap::IndexMap;
+
+use crate::{ButtonColor, ConfigProblem};
+use crate::animation::Effect;
+use crate::device::{KeyKind, PhysicalKey};
+use crate::render::TextAlign;
+
+use super::{ButtonTemplate, PhysicalKeyTemplate, SetupTemplate, StateTemplate, SETUP_BACK, SETUP_HOME};
+
+
+/// Where the parts of the config are in the json, they may come
+/// from the top level or from a device template (e.g. `devices.Mini.`)
+pub (super) struct ConfigPaths {
+    device: String,
+    // the entries of the device template, e.g. "controls.mute"
+    local: HashSet<String>,
+}
+
+impl ConfigPaths {
+
+    pub fn new(device: &str, local: HashSet<String>) -> Self {
+        ConfigPaths { device: String::from(device), local }
+    }
+
+    /// a part of the device template
+    pub fn of(&self, part: &str) -> String {
+        format!("{}{}", self.device, part)
+    }
+
+    /// an entry of the controls, setups or grids
+    pub fn entry(&self, part: &str, name: &str) -> String {
+        let p = format!("{}.{}", part, name);
+        if self.local.contains(&p) { format!("{}{}", self.device, p) } else { p }
+    }
+}
+
+/// Everything the config refers to
+pub (super) struct Validator<'a> {
+    pub home: &'a Path,
+    pub keys: &'a HashMap<String,PhysicalKey>,
+    pub controls: &'a IndexMap<String,ButtonTemplate>,
+    pub setups: Vec<&'a str>,
+    pub functions: Vec<&'a str>,
+    problems: Vec<ConfigProblem>,
+}
+
+impl <'a> Validator<'a> {
+
+    pub fn new(home: &'a Path, keys: &'a HashMap<String,PhysicalKey>, controls: &'a IndexMap<String,ButtonTemplate>,
+        setups: Vec<&'a str>, functions: Vec<&'a str>) -> Self
+    {
+        Validator { home, keys, controls, setups, functions, problems: Vec::new() }
+    }
+
+    pub fn problems(self) -> Vec<ConfigProblem> {
+        self.problems
+    }
+
+    fn problem(&mut self, path: &str, message: String) {
+        self.problems.push(ConfigProblem { path: String::from(path), message });
+    }
+
+    pub fn wiring(&mut self, path: &str, wiring: &IndexMap<String,PhysicalKeyTemplate>) {
+
+        let mut ids = HashMap::new();
+
+        for (name, pt) in wiring {
+            let p = format!("{}.{}", path, name);
+            if let Some(k) = &pt.kind {
+                if let Err(e) = KeyKind::from_str(k) {
+                    self.problem(&format!("{}.kind", p), e.to_string());
+                    continue;
+                }
+            }
+            // by key id, a note and a controller may have the same number
+            match pt.into_key(name) {
+                Ok(key) => if let Some(other) = ids.insert(key.id, name) {
+                    self.problem(&format!("{}.id", p), format!("duplicate id {} (also used by {})", pt.id, other));
+                },
+                Err(e) => self.problem(&format!("{}.id", p), e.to_string())
+            }
+        }
+    }
+
+    pub fn controls(&mut self, paths: &ConfigPaths) {
+
+        for (name, bt) in self.controls {
+
+            let p = paths.entry("controls", name);
+            let states = state_names(bt);
+
+            self.look(&p, &bt.color, &bt.image, &bt.overlays, &bt.effect, &bt.font, &bt.text_align, &bt.text_color);
+            self.actions(&p, &states, [&bt.on_down, &bt.on_up, &bt.on_value, &bt.on_long_press, &bt.on_double_tap],
+                &bt.switch_button_state, &bt.switch_deck_setup, &bt.long_press_setup);
+
+            for (sn, st) in bt.states.iter().flatten() {
+                let sp = format!("{}.states.{}", p, sn);
+                self.state(&sp, &states, st);
+            }
+        }
+    }
+
+    fn state(&mut self, path: &str, states: &HashSet<&str>, st: &StateTemplate) {
+        self.look(path, &st.color, &st.image, &st.overlays, &st.effect, &st.font, &st.text_align, &st.text_color);
+        self.actions(path, states, [&st.on_down, &st.on_up, &st.on_value, &st.on_long_press, &st.on_double_tap],
+            &st.switch_button_state, &st.switch_deck_setup, &st.long_press_setup);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn look(&mut self, path: &str, color: &Option<String>, image: &Option<String>, overlays: &Option<Vec<String>>,
+        effect: &Option<String>, font: &Option<String>, text_align: &Option<String>, text_color: &Option<String>)
+    {
+        for (field, c) in [("color", color), ("text_color", text_color)] {
+            if let Some(c) = c {
+                if let Err(e) = ButtonColor::from_str(c) {
+                    self.problem(&format!("{}.{}", path, field), e.to_string());
+                }
+            }
+        }
+
+        if let Some(i) = image {
+            self.file(&format!("{}.image", path), i);
+        }
+        for (n, o) in overlays.iter().flatten().enumerate() {
+            self.file(&format!("{}.overlays[{}]", path, n), o);
+        }
+        if let Some(f) = font {
+            self.file(&format!("{}.font", path), f);
+        }
+
+        if let Some(e) = effect {
+            if let Err(e) = Effect::from_str(e) {
+                self.problem(&format!("{}.effect", path), e.to_string());
+            }
+        }
+        if let Some(a) = text_align {
+            if let Err(e) = TextAlign::from_str(a) {
+                self.problem(&format!("{}.text_align", path), e.to_string());
+            }
+        }
+    }
+
+    fn actions(&mut self, path: &str, states: &HashSet<&str>, functions: [&Option<String>; 5],
+        switch_state: &Option<String>, switch_setup: &Option<String>, long_press_setup: &Option<String>)
+    {
+        let names = ["on_down", "on_up", "on_value", "on_long_press", "on_double_tap"];
+        for (field, f) in names.iter().zip(functions) {
+            if let Some(f) = f {
+                if !self.functions.contains(&f.as_str()) {
+                    self.problem(&format!("{}.{}", path, field), format!("unknown function '{}'", f));
+                }
+            }
+        }
+
+        if let Some(s) = switch_state {
+            if !states.contains(s.as_str()) {
+                self.problem(&format!("{}.switch_button_state", path), format!("unknown state '{}'", s));
+            }
+        }
+
+        for (field, s) in [("switch_deck_setup", switch_setup), ("long_press_setup", long_press_setup)] {
+            if let Some(s) = s {
+                if s != SETUP_BACK && s != SETUP_HOME && !self.setups.contains(&s.as_str()) {
+                    self.problem(&format!("{}.{}", path, field), format!("unknown setup '{}'", s));
+                }
+            }
+        }
+    }
+
+    pub fn setups(&mut self, paths: &ConfigPaths, part: &str, setups: &IndexMap<String,SetupTemplate>) {
+
+        for (name, st) in setups {
+
+            let p = paths.entry(part, name);
+
+            if name == SETUP_BACK || name == SETUP_HOME {
+                self.problem(&p, format!("'{}' is reserved, the setup cannot be a switch target", name));
+            }
+
+            if let Some(i) = &st.image {
+                self.file(&format!("{}.image", p), i);
+            }
+
+            for (key, rt) in &st.mapping {
+                let mp = format!("{}.mapping.{}", p, key);
+                self.key(&mp, key);
+                match self.controls.get(&rt.control) {
+                    Some(bt) => if let Some(s) = &rt.state {
+                        if !state_names(bt).contains(s.as_str()) {
+                            self.problem(&format!("{}.state", mp), format!("control '{}' has no state '{}'", rt.control, s));
+                        }
+                    },
+                    None => self.problem(&format!("{}.control", mp), format!("unknown control '{}'", rt.control))
+                }
+            }
+
+            for (n, c) in st.controls.iter().flatten().enumerate() {
+                if !self.controls.contains_key(c) {
+                    self.problem(&format!("{}.controls[{}]", p, n), format!("unknown control '{}'", c));
+                }
+            }
+            for (n, k) in st.keys.iter().flatten().enumerate() {
+                self.key(&format!("{}.keys[{}]", p, n), k);
+            }
+            for (field, k) in [("prev", &st.prev), ("next", &st.next)] {
+                if let Some(k) = k {
+                    self.key(&format!("{}.{}", p, field), k);
+                }
+            }
+            for (field, i) in [("prev_image", &st.prev_image), ("next_image", &st.next_image)] {
+                if let Some(i) = i {
+                    self.file(&format!("{}.{}", p, field), i);
+                }
+            }
+        }
+    }
+
+    fn key(&mut self, path: &str, name: &str) {
+        if !self.keys.contains_key(name) {
+            self.problem(path, format!("unknown key '{}'", name));
+        }
+    }
+
+    fn file(&mut self, path: &str, file: &str) {
+        if !self.home.join(file).exists() {
+            self.problem(path, format!("missing file '{}'", self.home.join(file).display()));
+        }
+    }
+
+}
+
+
+// a control without states has one, named "default"
+fn state_names(bt: &ButtonTemplate) -> HashSet<&str> {
+    match &bt.states {
+        Some(s) => s.keys().map(|s| s.as_str()).collect(),
+        None => HashSet::from(["default"])
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // the problems of a config with one key A1, one setup and the function "play"
+    fn problems(wiring: &str, controls: &str, setups: &str) -> Vec<(String,String)> {
+
+        let home = Path::new(env!("CARGO_MANIFEST_DIR")).join("demo");
+        let a1 = PhysicalKey { id: 0, name: String::from("A1"), kind: KeyKind::Key, on_off_threshold: None };
+        let keys = HashMap::from([(String::from("A1"), a1)]);
+
+        let wiring: IndexMap<String,PhysicalKeyTemplate> = serde_json::from_str(wiring).unwrap();
+        let controls: IndexMap<String,ButtonTemplate> = serde_json::from_str(controls).unwrap();
+        let setups: IndexMap<String,SetupTemplate> = serde_json::from_str(setups).unwrap();
+
+        let paths = ConfigPaths::new("deck.", HashSet::new());
+        let mut v = Validator::new(&home, &keys, &controls, setups.keys().map(|s| s.as_str()).collect(), vec!["play"]);
+        v.wiring("wiring", &wiring);
+        v.controls(&paths);
+        v.setups(&paths, "setups", &setups);
+        v.problems().into_iter().map(|p| (p.path, p.message)).collect()
+    }
+
+    #[test]
+    fn problems_by_path() {
+
+        let home = Path::new(env!("CARGO_MANIFEST_DIR")).join("demo");
+        let setup = |mapping: &str| format!(r#"{{ "first": {{ "mapping": {} }} }}"#, mapping);
+
+        let cases = [
+            // unknown control
+            ("{}", "{}", setup(r#"{ "A1": { "control": "nope" } }"#),
+                "setups.first.mapping.A1.control", String::from("unknown control 'nope'")),
+            // unknown key
+            ("{}", r#"{ "c": {} }"#, setup(r#"{ "Z9": { "control": "c" } }"#),
+                "setups.first.mapping.Z9", String::from("unknown key 'Z9'")),
+            // unknown function
+            ("{}", r#"{ "c": { "on_down": "stop" } }"#, setup("{}"),
+                "controls.c.on_down", String::from("unknown function 'stop'")),
+            // bad color
+            ("{}", r#"{ "c": { "color": "reddish" } }"#, setup("{}"),
+                "controls.c.color", String::from("message `invalid color 'reddish'`")),
+            // duplicate id
+            (r#"{ "X": { "id": 1 }, "Y": { "id": 1 } }"#, "{}", setup("{}"),
+                "wiring.Y.id", String::from("duplicate id 1 (also used by X)")),
+            // missing file
+            ("{}", r#"{ "c": { "image": "nope.png" } }"#, setup("{}"),
+                "controls.c.image", format!("missing file '{}'", home.join("nope.png").display())),
+        ];
+
+        for (wiring, controls, setups, path, message) in cases {
+            assert_eq!(problems(wiring, controls, &setups), [(String::from(path), message)]);
+        }
+    }
+
+    #[test]
+    fn no_problems() {
+        let controls = r##"{ "c": { "image": "right.png", "color": "#ff0000", "on_down": "play", "switch_deck_setup": "first" } }"##;
+        let setups = r#"{ "first": { "mapping": { "A1": { "control": "c" } } } }"#;
+        assert!(problems(r#"{ "A1": { "id": 0 } }"#, controls, setups).is_empty());
+    }
+}
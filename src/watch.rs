@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{debug, trace};
+
+// how often the files are checked
+const POLL_INTERVAL: Duration = Duration::from_millis(1000);
+
+
+/// Watches the config file, its includes and the files the controls use
+/// by polling the modification times, driven by the deck thread like the
+/// animations. Directories are not watched, a new file needs a config change.
+pub (crate) struct ConfigWatcher {
+    files: Vec<PathBuf>,
+    stamps: HashMap<PathBuf, SystemTime>,
+    // files that changed since the last reload
+    changed: Vec<PathBuf>,
+    next: Instant,
+}
+
+impl ConfigWatcher {
+
+    pub fn new(files: Vec<PathBuf>) -> Self {
+        debug!("watching {:?}", files);
+        let stamps = scan(&files);
+        ConfigWatcher {
+            files,
+            stamps,
+            changed: Vec::new(),
+            next: Instant::now() + POLL_INTERVAL,
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        Some(self.next)
+    }
+
+    /// the files that changed, once they stopped changing (editors often
+    /// write a file in several steps). Empty if there is nothing to reload.
+    pub fn poll(&mut self, now: Instant) -> Vec<PathBuf> {
+
+        if now < self.next {
+            return Vec::new();
+        }
+        self.next = now + POLL_INTERVAL;
+
+        let stamps = scan(&self.files);
+
+        let mut changed: Vec<PathBuf> = stamps.iter()
+            .filter(|(p,t)| self.stamps.get(*p) != Some(*t))
+            .map(|(p,_)| p.clone())
+            .collect();
+        // removed files
+        changed.extend(self.stamps.keys().filter(|p| !stamps.contains_key(*p)).cloned());
+
+        self.stamps = stamps;
+
+        if changed.is_empty() {
+            // quiet again, report what changed before
+            return std::mem::take(&mut self.changed);
+        }
+
+        trace!("changed: {:?}", changed);
+        for p in changed {
+            if !self.changed.contains(&p) {
+                self.changed.push(p);
+            }
+        }
+
+        Vec::new()
+    }
+
+}
+
+
+// modification time of the files that exist
+fn scan(files: &[PathBuf]) -> HashMap<PathBuf, SystemTime> {
+    files.iter()
+        .filter_map(|f| modified(f).map(|t| (f.clone(), t)))
+        .collect()
+}
+
+fn modified(file: &Path) -> Option<SystemTime> {
+    let meta = std::fs::metadata(file).ok()?;
+    if meta.is_file() { meta.modified().ok() } else { None }
+}
@@ -0,0 +1,198 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use buttondeck::{ButtonDeckBuilder, ButtonDecks, DeviceEvent, DeviceKind, VirtualDevice, VirtualDeviceHandle};
+
+
+// a config from tests/configs
+fn config(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("configs").join(name)
+}
+
+// run a deck with the config on a virtual device with one row of keys
+fn start(config: &Path, keys: usize) -> (ButtonDecks, VirtualDeviceHandle) {
+    start_with(config, keys, |b| b)
+}
+
+fn start_with<F>(config: &Path, keys: usize, more: F) -> (ButtonDecks, VirtualDeviceHandle)
+    where F: FnOnce(ButtonDeckBuilder<()>) -> ButtonDeckBuilder<()>
+{
+    let (device, handle) = VirtualDevice::new("virtual", keys);
+
+    let builder = more(ButtonDeckBuilder::<()>::new(DeviceKind::StreamDeck))
+        .with_config(config)
+        .with_strict_config(true)
+        .with_virtual_device(device);
+
+    let mut decks = ButtonDecks::new();
+    decks.spawn("test", builder).expect("deck");
+
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !handle.is_connected() {
+        assert!(Instant::now() < deadline, "virtual device not started");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    (decks, handle)
+}
+
+// what the deck sent to the device until it was quiet for a while
+fn drawn(handle: &VirtualDeviceHandle) -> Vec<String> {
+    let mut drawn = Vec::new();
+    while let Some(e) = handle.recv_timeout(Duration::from_millis(200)) {
+        match e {
+            DeviceEvent::SetColor(k, c) => drawn.push(format!("{} #{:06x}", k, c.rgb)),
+            DeviceEvent::SetImage(k, i) => {
+                let file = i.path().and_then(|p| p.file_name()).map(|f| f.to_string_lossy().into_owned());
+                drawn.push(format!("{} {}", k, file.unwrap_or_default()))
+            },
+            DeviceEvent::SetFrame(k, f) => {
+                let text = f.label.map(|l| l.text).unwrap_or_default();
+                drawn.push(format!("{} '{}'", k, text))
+            },
+            other => drawn.push(format!("{:?}", other)),
+        }
+    }
+    drawn
+}
+
+
+#[test]
+fn press_keys() {
+
+    let (decks, handle) = start(&config("virtual.json"), 2);
+
+    assert_eq!(drawn(&handle), [ "0 #000000", "1 right.png" ]);
+
+    // switch_button_state
+    handle.press(0).unwrap();
+    assert_eq!(drawn(&handle), [ "0 #ff0000" ]);
+
+    // switch_deck_setup, the light keeps its state
+    handle.press(1).unwrap();
+    assert_eq!(drawn(&handle), [ "0 #0000ff", "1 #ff0000" ]);
+
+    // and back
+    handle.press(0).unwrap();
+    assert_eq!(drawn(&handle), [ "0 #ff0000", "1 right.png" ]);
+
+    decks.shutdown();
+    assert!(matches!(handle.drain().as_slice(), [DeviceEvent::Shutdown]));
+}
+
+#[test]
+fn touch_strip() {
+
+    let (decks, handle) = start(&config("touch.json"), 1);
+    assert_eq!(drawn(&handle), [ "0 #000000" ]);
+
+    // a short touch is a tap
+    handle.inject(DeviceEvent::Touch(0, false)).unwrap();
+    assert_eq!(drawn(&handle), [ "0 #ff0000" ]);
+
+    // a long one the long press
+    handle.inject(DeviceEvent::Touch(0, true)).unwrap();
+    assert_eq!(drawn(&handle), [ "0 #0000ff" ]);
+
+    decks.shutdown();
+}
+
+#[test]
+fn long_press_during_event_stream() {
+
+    let (decks, handle) = start(&config("touch.json"), 1);
+    assert_eq!(drawn(&handle), [ "0 #000000" ]);
+
+    // a fader that moves all the time must not hold back the long press (600ms)
+    handle.button_down(0, 1.0).unwrap();
+    let mut during = Vec::new();
+    let until = Instant::now() + Duration::from_millis(1200);
+    while Instant::now() < until {
+        during.extend(handle.try_recv());
+        handle.inject(DeviceEvent::ButtonValue(5, 0.5)).unwrap();
+    }
+    assert!(matches!(during.as_slice(), [DeviceEvent::SetColor(0, c)] if c.rgb == 0x0000ff), "{:?}", during);
+
+    decks.shutdown();
+}
+
+#[test]
+fn dials_are_not_drawn() {
+
+    let (decks, handle) = start(&config("dial.json"), 2);
+    assert_eq!(drawn(&handle), [ "0 #ff0000" ]);
+
+    // pushing the dial switches the state, there is nothing to show
+    handle.press(1).unwrap();
+    assert_eq!(drawn(&handle), Vec::<String>::new());
+
+    decks.shutdown();
+}
+
+
+
+// the light control of reload.json, in an included file
+fn light(on: &str) -> String {
+    format!(r##"{{
+        "controls": {{
+            "light": {{
+                "states": {{
+                    "off": {{ "color": "#000000", "switch_button_state": "on" }},
+                    "on":  {{ "color": "{}", "switch_button_state": "off" }}
+                }}
+            }}
+        }}
+    }}"##, on)
+}
+
+#[test]
+fn hot_reload() {
+
+    let dir = std::env::temp_dir().join(format!("buttondeck-reload-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("light.json"), light("#ff0000")).unwrap();
+    std::fs::write(dir.join("reload.json"), r#"{
+        "deck": {
+            "include": [ "light.json" ],
+            "setups": { "first": { "mapping": { "A1": { "control": "light" } } } }
+        }
+    }"#).unwrap();
+
+    let (decks, handle) = start_with(&dir.join("reload.json"), 1, |b| b.with_hot_reload(true));
+
+    assert_eq!(drawn(&handle), [ "0 #000000" ]);
+    handle.press(0).unwrap();
+    assert_eq!(drawn(&handle), [ "0 #ff0000" ]);
+
+    // a change of the included file is seen, the button stays on
+    std::fs::write(dir.join("light.json"), light("#00ff00")).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut reloaded = Vec::new();
+    while reloaded.is_empty() && Instant::now() < deadline {
+        reloaded = drawn(&handle);
+        // the reload sends the wiring again
+        reloaded.retain(|d| !d.starts_with("SetWiring"));
+    }
+    assert_eq!(reloaded, [ "0 #00ff00" ]);
+
+    drop(decks);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn grid_pages() {
+
+    let (decks, handle) = start(&config("grid.json"), 3);
+
+    // the controls fit, no page buttons
+    assert_eq!(drawn(&handle), [ "0 #000001", "1 #ffffff", "2 #000000" ]);
+
+    // four controls on one key, the others flip the pages
+    handle.press(1).unwrap();
+    assert_eq!(drawn(&handle), [ "0 #000001", "1 '<'", "2 '>'" ]);
+    handle.press(2).unwrap();
+    assert_eq!(drawn(&handle), [ "0 #000002", "1 '<'", "2 '>'" ]);
+
+    drop(decks);
+}